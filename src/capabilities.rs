@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::io::{Error as IoError, Read};
+use libc::{c_int, c_ulong, prctl, syscall, SYS_capset};
+
+/// Capability names in kernel numbering order, see linux/capability.h.
+const CAPABILITIES: [&str; 41] = [
+    "CHOWN", "DAC_OVERRIDE", "DAC_READ_SEARCH", "FOWNER", "FSETID", "KILL", "SETGID", "SETUID",
+    "SETPCAP", "LINUX_IMMUTABLE", "NET_BIND_SERVICE", "NET_BROADCAST", "NET_ADMIN", "NET_RAW",
+    "IPC_LOCK", "IPC_OWNER", "SYS_MODULE", "SYS_RAWIO", "SYS_CHROOT", "SYS_PTRACE", "SYS_PACCT",
+    "SYS_ADMIN", "SYS_BOOT", "SYS_NICE", "SYS_RESOURCE", "SYS_TIME", "SYS_TTY_CONFIG", "MKNOD",
+    "LEASE", "AUDIT_WRITE", "AUDIT_CONTROL", "SETFCAP", "MAC_OVERRIDE", "MAC_ADMIN", "SYSLOG",
+    "WAKE_ALARM", "BLOCK_SUSPEND", "AUDIT_READ", "PERFMON", "BPF", "CHECKPOINT_RESTORE",
+];
+
+/// The capabilities a container keeps unless told otherwise, the same as docker.
+pub const DEFAULT_CAPABILITIES: [&str; 14] = [
+    "CHOWN", "DAC_OVERRIDE", "FSETID", "FOWNER", "MKNOD", "NET_RAW", "SETGID", "SETUID",
+    "SETFCAP", "SETPCAP", "NET_BIND_SERVICE", "SYS_CHROOT", "KILL", "AUDIT_WRITE",
+];
+
+const PR_SET_KEEPCAPS: c_int = 8;
+const PR_CAPBSET_DROP: c_int = 24;
+const PR_CAP_AMBIENT: c_int = 47;
+const PR_CAP_AMBIENT_CLEAR_ALL: c_ulong = 4;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+/// Normalize `cap_sys_admin`, `SYS_ADMIN` or `CAP_SYS_ADMIN` to `SYS_ADMIN`.
+fn normalize(name: &str) -> String {
+    let upper = name.trim().to_uppercase();
+    if upper.starts_with("CAP_") { upper[4..].to_string() } else { upper }
+}
+
+fn cap_number(name: &str) -> Option<usize> {
+    CAPABILITIES.iter().position(|c| *c == name)
+}
+
+/// Compute the container's capability set from the defaults and the
+/// `--cap-add`/`--cap-drop`/`--privileged` flags. `ALL` is accepted in both lists.
+pub fn resolve_capabilities(cap_add: &[String], cap_drop: &[String], privileged: bool) -> Result<Vec<String>, String> {
+    if privileged {
+        return Ok(CAPABILITIES.iter().map(|c| c.to_string()).collect());
+    }
+    let validate = |names: &[String]| -> Result<Vec<String>, String> {
+        names.iter().map(|n| {
+            let cap = normalize(n);
+            if cap == "ALL" || cap_number(&cap).is_some() {
+                Ok(cap)
+            } else {
+                Err(format!("Unknown capability {:?}", n))
+            }
+        }).collect()
+    };
+    let add = validate(cap_add)?;
+    let drop = validate(cap_drop)?;
+
+    let mut caps: Vec<String> = if drop.iter().any(|c| c == "ALL") {
+        Vec::new()
+    } else {
+        DEFAULT_CAPABILITIES.iter().map(|c| c.to_string()).filter(|c| !drop.contains(c)).collect()
+    };
+    if add.iter().any(|c| c == "ALL") {
+        caps = CAPABILITIES.iter().map(|c| c.to_string()).filter(|c| !drop.contains(c)).collect();
+    } else {
+        for cap in add {
+            if !caps.contains(&cap) {
+                caps.push(cap);
+            }
+        }
+    }
+    Ok(caps)
+}
+
+fn last_cap() -> usize {
+    let mut content = String::new();
+    File::open("/proc/sys/kernel/cap_last_cap")
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .ok()
+        .and_then(|_| content.trim().parse().ok())
+        .unwrap_or(CAPABILITIES.len() - 1)
+}
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: c_int
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32
+}
+
+fn cap_numbers(caps: &[String]) -> Result<Vec<usize>, String> {
+    caps.iter().map(|name| cap_number(name).ok_or(format!("Unknown capability {:?}", name))).collect()
+}
+
+/// Drop every capability not in `caps` from the bounding set. Needs
+/// CAP_SETPCAP, so it has to happen before switching to a non-root user.
+pub fn drop_bounding_set(caps: &[String]) -> Result<(), String> {
+    let keep = cap_numbers(caps)?;
+    for cap in 0..last_cap() + 1 {
+        if keep.contains(&cap) {
+            continue;
+        }
+        if unsafe { prctl(PR_CAPBSET_DROP, cap as c_ulong, 0, 0, 0) } != 0 {
+            return Err(format!("Can't drop {} from the bounding set : {}",
+                               CAPABILITIES.get(cap).unwrap_or(&"?"), IoError::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/// Keep the permitted set across the setuid() that switches to a non-root user.
+pub fn set_keep_caps(keep: bool) -> Result<(), String> {
+    if unsafe { prctl(PR_SET_KEEPCAPS, keep as c_ulong, 0, 0, 0) } != 0 {
+        return Err(format!("Can't set keepcaps : {}", IoError::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Restrict the permitted and effective sets of the calling process to `caps`
+/// and empty its inheritable and ambient sets. Must be the last privileged
+/// step before exec.
+pub fn apply_capabilities(caps: &[String]) -> Result<(), String> {
+    let keep = cap_numbers(caps)?;
+    let last = last_cap();
+
+    let mut data = [CapUserData::default(); 2];
+    for &cap in &keep {
+        if cap > last {
+            continue;
+        }
+        let bit = 1u32 << (cap % 32);
+        data[cap / 32].effective |= bit;
+        data[cap / 32].permitted |= bit;
+    }
+    // the inheritable set stays empty: filled, it would hand the capabilities
+    // to any program with matching file capabilities, even for a non-root
+    // user (CVE-2022-24769)
+    let mut header = CapUserHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    if unsafe { syscall(SYS_capset, &mut header as *mut _, data.as_ptr()) } != 0 {
+        return Err(format!("Can't set capabilities : {}", IoError::last_os_error()));
+    }
+
+    // like docker, leave the ambient set empty: a non-root user keeps the
+    // capabilities in its bounding set but loses them at exec
+    if unsafe { prctl(PR_CAP_AMBIENT, PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) } != 0 {
+        warn!("Ambient capabilities are not supported: {}", IoError::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn names_are_normalized() {
+        assert_eq!(normalize("cap_sys_admin"), "SYS_ADMIN");
+        assert_eq!(normalize(" Net_Admin "), "NET_ADMIN");
+        assert_eq!(cap_number("SYS_ADMIN"), Some(21));
+        assert_eq!(cap_number("CHECKPOINT_RESTORE"), Some(40));
+        assert_eq!(cap_number("NOPE"), None);
+    }
+
+    #[test]
+    fn add_and_drop() {
+        let caps = resolve_capabilities(&strings(&["cap_sys_admin", "CHOWN"]), &strings(&["net_raw"]), false).unwrap();
+        assert!(caps.contains(&"SYS_ADMIN".to_string()));
+        assert!(!caps.contains(&"NET_RAW".to_string()));
+        assert_eq!(caps.iter().filter(|c| *c == "CHOWN").count(), 1);
+        assert_eq!(caps.len(), DEFAULT_CAPABILITIES.len());
+    }
+
+    #[test]
+    fn all_keyword() {
+        assert!(resolve_capabilities(&[], &strings(&["all"]), false).unwrap().is_empty());
+        assert_eq!(resolve_capabilities(&strings(&["ALL"]), &strings(&["MKNOD"]), false).unwrap().len(), CAPABILITIES.len() - 1);
+        assert_eq!(resolve_capabilities(&[], &strings(&["ALL"]), true).unwrap().len(), CAPABILITIES.len());
+    }
+
+    #[test]
+    fn unknown_capability() {
+        assert!(resolve_capabilities(&strings(&["CAP_NOPE"]), &[], false).is_err());
+        assert!(resolve_capabilities(&[], &strings(&["NOPE"]), false).is_err());
+    }
+}
@@ -1,6 +1,10 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use subsystem::{ResourceConfig, subsystems_ins};
-use libc::pid_t;
+use subsystem::{ResourceConfig, FreezerSubSystem, subsystems_ins, is_cgroup_v2};
+use libc::{pid_t, access, W_OK};
+use stats::{CgroupStats, read_cgroup_stats};
 
 pub struct CgroupManager {
     pub path: &Path,
@@ -26,6 +30,32 @@ pub impl CgroupManager {
         Ok(())
     }
 
+    /// Apply `res` to every subsystem of a live cgroup, carrying on past failures
+    /// so the caller can report which subsystems took the new limits.
+    pub fn update(&self, res: &ResourceConfig) -> Vec<(String, Result<(), String>)> {
+        let mut results = Vec::new();
+        for sub_sys_ins in subsystems_ins {
+            // device rules are fixed at creation, re-applying them would drop --device entries
+            if sub_sys_ins.name() == "devices" {
+                continue;
+            }
+            results.push((sub_sys_ins.name(), sub_sys_ins.set(self.path, res)));
+        }
+        results
+    }
+
+    pub fn freeze(&self) -> Result<(), String> {
+        FreezerSubSystem{}.freeze(self.path, true)
+    }
+
+    pub fn thaw(&self) -> Result<(), String> {
+        FreezerSubSystem{}.freeze(self.path, false)
+    }
+
+    pub fn stats(&self) -> Result<CgroupStats, String> {
+        read_cgroup_stats(self.path)
+    }
+
     pub fn destroy(&self) -> Result<(), String> {
         for sub_sys_ins in subsystems_ins {
                 sub_sys_ins.remove(self.path)?;
@@ -33,3 +63,33 @@ pub impl CgroupManager {
         Ok(())
     }
 }
+
+/// The cgroup v2 subtree systemd delegated to the calling user, relative to
+/// the hierarchy root, e.g. `user.slice/user-1000.slice/user@1000.service`.
+/// None when there's no writable delegation, rootless containers then run
+/// without resource limits.
+pub fn delegated_cgroup_root() -> Option<PathBuf> {
+    if !is_cgroup_v2() {
+        return None;
+    }
+    let mut content = String::new();
+    File::open("/proc/self/cgroup").and_then(|mut f| f.read_to_string(&mut content)).ok()?;
+    let own = content.lines().find(|l| l.starts_with("0::"))?[3..].trim_start_matches('/').to_string();
+    // walk up from our own cgroup to the user's service, the delegation point
+    let mut candidate = PathBuf::from(&own);
+    loop {
+        let name = candidate.file_name()?.to_string_lossy().into_owned();
+        if name.starts_with("user@") && name.ends_with(".service") {
+            break;
+        }
+        if !candidate.pop() {
+            return None;
+        }
+    }
+    let full = Path::new("/sys/fs/cgroup").join(&candidate);
+    let c_path = CString::new(full.join("cgroup.subtree_control").to_string_lossy().as_bytes()).ok()?;
+    if unsafe { access(c_path.as_ptr(), W_OK) } != 0 {
+        return None;
+    }
+    Some(candidate)
+}
@@ -0,0 +1,52 @@
+use std::env;
+use std::fs::File;
+use std::path::Path;
+use libc::geteuid;
+use container::DEFAULT_INFO_LOCATION;
+
+pub const DAEMON_CONFIG: &str = "/etc/mydocker/daemon.json";
+pub const DEFAULT_DATA_ROOT: &str = "/root";
+
+/// Running without real root: everything lives under the user's XDG dirs
+/// and the container gets a user namespace mapping that user to root.
+pub fn is_rootless() -> bool {
+    unsafe { geteuid() != 0 }
+}
+
+/// Where container state and logs go, `$XDG_RUNTIME_DIR/mydocker` when rootless.
+pub fn info_location() -> String {
+    if !is_rootless() {
+        return DEFAULT_INFO_LOCATION.to_string();
+    }
+    let runtime_dir = env::var("XDG_RUNTIME_DIR")
+        .unwrap_or_else(|_| format!("/run/user/{}", unsafe { geteuid() }));
+    format!("{}/mydocker", runtime_dir)
+}
+
+/// Where images, write layers and mount points go, `$XDG_DATA_HOME/mydocker` when rootless.
+pub fn data_root() -> String {
+    if !is_rootless() {
+        return DEFAULT_DATA_ROOT.to_string();
+    }
+    let data_home = env::var("XDG_DATA_HOME")
+        .unwrap_or_else(|_| format!("{}/.local/share", env::var("HOME").unwrap_or_default()));
+    format!("{}/mydocker", data_home)
+}
+
+/// Defaults shared by every container, read from /etc/mydocker/daemon.json.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct DaemonConfig {
+    pub userns_remap: String
+}
+
+impl DaemonConfig {
+    pub fn load() -> Result<DaemonConfig, String> {
+        let path = Path::new(DAEMON_CONFIG);
+        if !path.exists() {
+            return Ok(DaemonConfig::default());
+        }
+        let f = File::open(path).map_err(|e| format!("Can't open {:?} : {}", path, e))?;
+        serde_json::from_reader(f).map_err(|e| format!("Invalid {:?} : {}", path, e))
+    }
+}
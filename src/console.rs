@@ -0,0 +1,391 @@
+use std::fs::{File, OpenOptions, create_dir_all, remove_file};
+use std::io::{self, Read, Write, ErrorKind, Error as IoError};
+use std::mem::zeroed;
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::ptr::null_mut;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+use libc::{c_void, sigset_t, sigemptyset, sigaddset, sigwait, pthread_sigmask, pipe2, send};
+use libc::{O_CLOEXEC, SIG_BLOCK, SIGWINCH, EIO, STDIN_FILENO, MSG_DONTWAIT, MSG_NOSIGNAL};
+use config::info_location;
+use tty::{Pty, RawMode, window_size, set_window_size, eof_char};
+
+pub const DEFAULT_DETACH_KEYS: &str = "ctrl-p,ctrl-q";
+const CONSOLE_SOCKET: &str = "console.sock";
+const LOG_NAME: &str = "container.log";
+// small enough to fit into a fresh client's socket buffer
+const MAX_REPLAY: usize = 64 << 10;
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// attach clients send framed messages: kind, big endian u16 length, payload
+const FRAME_INPUT: u8 = 0;
+const FRAME_RESIZE: u8 = 1;
+// the client's stdin ended
+const FRAME_CLOSE: u8 = 2;
+
+/// Our side of the container's stdio: a pty master, or pipes when there is no tty.
+pub struct Console {
+    input: File,
+    output: File,
+    tty: bool
+}
+
+/// The container's ends of the console, to become its fds 0, 1 and 2.
+pub type ContainerStdio = [File; 3];
+
+impl Console {
+    pub fn from_pty(pty: Pty) -> Result<(Console, ContainerStdio), String> {
+        let stdio = [clone(&pty.slave)?, clone(&pty.slave)?, pty.slave];
+        let console = Console { input: clone(&pty.master)?, output: pty.master, tty: true };
+        Ok((console, stdio))
+    }
+
+    pub fn from_pipes() -> Result<(Console, ContainerStdio), String> {
+        let (stdin_reader, stdin_writer) = pipe()?;
+        let (output_reader, output_writer) = pipe()?;
+        let stdio = [stdin_reader, clone(&output_writer)?, output_writer];
+        Ok((Console { input: stdin_writer, output: output_reader, tty: false }, stdio))
+    }
+}
+
+fn clone(f: &File) -> Result<File, String> {
+    f.try_clone().map_err(|e| format!("Clone console fd error {}", e))
+}
+
+fn pipe() -> Result<(File, File), String> {
+    let mut fds = [0; 2];
+    if unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) } != 0 {
+        return Err(format!("Can't create pipe : {}", IoError::last_os_error()));
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+pub fn console_socket_path(container_name: &str) -> String {
+    format!("{}/{}/{}", info_location(), container_name, CONSOLE_SOCKET)
+}
+
+/// Serve the container's console from a thread of the monitor: all output
+/// is appended to the container log and relayed to every client of the
+/// console socket, so the container survives its attachers. The thread
+/// ends once the container's side of the console is closed.
+///
+/// With `replay` the output from before the first client connected is held
+/// for it, `run` only attaches once the command is already running.
+pub fn spawn_console_server(container_name: &str, console: Console, replay: bool) -> Result<JoinHandle<()>, String> {
+    let dir = format!("{}/{}", info_location(), container_name);
+    create_dir_all(&dir).map_err(|e| format!("Can't create {} : {}", dir, e))?;
+    let socket_path = console_socket_path(container_name);
+    // a leftover from a container of the same name would make bind fail
+    let _ = remove_file(&socket_path);
+    let listener = UnixListener::bind(&socket_path)
+        .map_err(|e| format!("Can't listen on {} : {}", socket_path, e))?;
+    let log = OpenOptions::new().create(true).append(true)
+        .open(format!("{}/{}", dir, LOG_NAME))
+        .map_err(|e| format!("Can't open container log : {}", e))?;
+
+    Ok(thread::spawn(move || {
+        serve(listener, &socket_path, console, log, replay);
+        let _ = remove_file(&socket_path);
+    }))
+}
+
+struct Clients {
+    streams: Vec<UnixStream>,
+    /// Output waiting for the first client, None once it connected.
+    replay: Option<Vec<u8>>
+}
+
+fn serve(listener: UnixListener, socket_path: &str, console: Console, mut log: File, replay: bool) {
+    let clients = Arc::new(Mutex::new(Clients {
+        streams: Vec::new(),
+        replay: if replay { Some(Vec::new()) } else { None },
+    }));
+    // None once a client closed it
+    let input = Arc::new(Mutex::new(Some(console.input)));
+    let pty = if console.tty { Some(console.output.as_raw_fd()) } else { None };
+
+    let closing = Arc::new(AtomicBool::new(false));
+    let accepted = clients.clone();
+    let stop = closing.clone();
+    let acceptor = thread::spawn(move || {
+        for stream in listener.incoming() {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Accept console client error {}", e);
+                    continue;
+                }
+            };
+            match stream.try_clone() {
+                Ok(writer) => {
+                    let mut clients = accepted.lock().unwrap();
+                    match clients.replay.take() {
+                        Some(ref held) if !send_nonblocking(&writer, held) => {
+                            warn!("Replay console output error");
+                            let _ = writer.shutdown(Shutdown::Both);
+                        }
+                        _ => clients.streams.push(writer),
+                    }
+                }
+                Err(e) => {
+                    warn!("Clone console client error {}", e);
+                    continue;
+                }
+            }
+            let input = input.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve_client(stream, &input, pty) {
+                    warn!("Console client error {}", e);
+                }
+            });
+        }
+    });
+
+    relay(console.output, &mut log, &clients);
+
+    // a short-lived command may be gone before `run` attached
+    let deadline = Instant::now() + REPLAY_TIMEOUT;
+    while clients.lock().unwrap().replay.is_some() && Instant::now() < deadline {
+        sleep(Duration::from_millis(10));
+    }
+    // the accept thread holds the listener and the console input, a restart
+    // serves the console anew; a connection wakes it up to see the flag
+    closing.store(true, Ordering::SeqCst);
+    match UnixStream::connect(socket_path) {
+        Ok(_) => if acceptor.join().is_err() {
+            warn!("Console accept thread panicked");
+        },
+        Err(e) => warn!("Wake console accept thread error {}", e),
+    }
+    // the clients must learn the output ended
+    let mut clients = clients.lock().unwrap();
+    clients.replay = None;
+    for c in clients.streams.drain(..) {
+        let _ = c.shutdown(Shutdown::Both);
+    }
+}
+
+fn relay(mut output: File, log: &mut File, clients: &Mutex<Clients>) {
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match output.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            // the last pty slave went away with the container
+            Err(ref e) if e.raw_os_error() == Some(EIO) => return,
+            Err(e) => {
+                error!("Read container output error {}", e);
+                return;
+            }
+        };
+        if let Err(e) = log.write_all(&buf[..n]) {
+            warn!("Write container log error {}", e);
+        }
+        let mut clients = clients.lock().unwrap();
+        if let Some(ref mut held) = clients.replay {
+            // beyond that, the log has it
+            let keep = n.min(MAX_REPLAY.saturating_sub(held.len()));
+            held.extend_from_slice(&buf[..keep]);
+            continue;
+        }
+        // a client that can't keep up or went away is dropped
+        clients.streams.retain(|c| {
+            let sent = send_nonblocking(c, &buf[..n]);
+            if !sent {
+                let _ = c.shutdown(Shutdown::Both);
+            }
+            sent
+        });
+    }
+}
+
+/// Send all of `buf` or fail, without ever waiting for the client: the
+/// reading side of the stream stays blocking, so this can't use O_NONBLOCK.
+fn send_nonblocking(client: &UnixStream, buf: &[u8]) -> bool {
+    let mut sent = 0;
+    while sent < buf.len() {
+        let n = unsafe {
+            send(client.as_raw_fd(), buf[sent..].as_ptr() as *const c_void, buf.len() - sent, MSG_DONTWAIT | MSG_NOSIGNAL)
+        };
+        if n < 0 {
+            if IoError::last_os_error().kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return false;
+        }
+        sent += n as usize;
+    }
+    true
+}
+
+fn serve_client(mut stream: UnixStream, input: &Mutex<Option<File>>, pty: Option<RawFd>) -> Result<(), String> {
+    let mut header = [0u8; 3];
+    loop {
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        }
+        let len = ((header[1] as usize) << 8) | header[2] as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).map_err(|e| e.to_string())?;
+        match header[0] {
+            FRAME_INPUT => if let Some(ref mut input) = *input.lock().unwrap() {
+                input.write_all(&payload).map_err(|e| e.to_string())?;
+            },
+            FRAME_RESIZE if len == 4 => if let Some(fd) = pty {
+                let rows = ((payload[0] as u16) << 8) | payload[1] as u16;
+                let cols = ((payload[2] as u16) << 8) | payload[3] as u16;
+                set_window_size(fd, rows, cols)?;
+            },
+            FRAME_CLOSE => {
+                let mut input = input.lock().unwrap();
+                match pty {
+                    // the master stays open for output, the line discipline signals the end
+                    Some(fd) => if let Some(ref mut input) = *input {
+                        input.write_all(&[eof_char(fd)?]).map_err(|e| e.to_string())?;
+                    },
+                    None => *input = None,
+                }
+            }
+            kind => return Err(format!("Unknown console frame {}", kind)),
+        }
+    }
+}
+
+fn write_frame(stream: &mut UnixStream, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let header = [kind, (payload.len() >> 8) as u8, payload.len() as u8];
+    stream.write_all(&header)?;
+    stream.write_all(payload)
+}
+
+fn send_window_size(stream: &mut UnixStream) -> Result<(), String> {
+    let (rows, cols) = window_size(STDIN_FILENO)?;
+    let payload = [(rows >> 8) as u8, rows as u8, (cols >> 8) as u8, cols as u8];
+    write_frame(stream, FRAME_RESIZE, &payload).map_err(|e| format!("Send window size error {}", e))
+}
+
+/// Parse docker's detach key format, e.g. `ctrl-p,ctrl-q` or `ctrl-a,d`.
+pub fn parse_detach_keys(spec: &str) -> Result<Vec<u8>, String> {
+    let mut keys = Vec::new();
+    for key in spec.split(',') {
+        let bytes = key.as_bytes();
+        let code = if bytes.len() == 1 {
+            bytes[0]
+        } else if key.starts_with("ctrl-") && bytes.len() == 6 {
+            match bytes[5] {
+                c @ b'a'..=b'z' => c - b'a' + 1,
+                c @ b'@' | c @ b'['..=b'_' => c - b'@',
+                _ => return Err(format!("Invalid detach key {}", key)),
+            }
+        } else {
+            return Err(format!("Invalid detach key {}", key));
+        };
+        keys.push(code);
+    }
+    Ok(keys)
+}
+
+/// Connect our stdio to a container's console, stdin only if `stdin` is set.
+/// Returns true when the user detached with `detach_keys`, false once the
+/// container's output ended.
+pub fn attach(container_name: &str, detach_keys: &[u8], stdin: bool) -> Result<bool, String> {
+    let socket_path = console_socket_path(container_name);
+    let mut stream = UnixStream::connect(&socket_path)
+        .map_err(|e| format!("Can't connect to console {} : {}", socket_path, e))?;
+    // like docker, the terminal is only taken over when keystrokes go to the container
+    let raw_mode = if stdin { RawMode::enter(STDIN_FILENO)? } else { None };
+    let detached = Arc::new(AtomicBool::new(false));
+
+    if raw_mode.is_some() {
+        if let Err(e) = send_window_size(&mut stream) {
+            warn!("{}", e);
+        }
+        // SIGWINCH is ignored by default, it only queues up for sigwait while
+        // blocked, and the helper threads inherit the mask
+        let mut winch: sigset_t = unsafe { zeroed() };
+        unsafe {
+            sigemptyset(&mut winch);
+            sigaddset(&mut winch, SIGWINCH);
+            pthread_sigmask(SIG_BLOCK, &winch, null_mut());
+        }
+        let mut resize = stream.try_clone().map_err(|e| format!("Clone console stream error {}", e))?;
+        thread::spawn(move || loop {
+            let mut sig = 0;
+            if unsafe { sigwait(&winch, &mut sig) } == 0 && sig == SIGWINCH {
+                if send_window_size(&mut resize).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    if stdin {
+        let mut input = stream.try_clone().map_err(|e| format!("Clone console stream error {}", e))?;
+        let keys = detach_keys.to_vec();
+        let detach = detached.clone();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            let mut stdin = stdin.lock();
+            let mut buf = [0u8; 1024];
+            let mut matched = 0;
+            loop {
+                let n = match stdin.read(&mut buf) {
+                    Ok(0) => {
+                        let _ = write_frame(&mut input, FRAME_CLOSE, &[]);
+                        return;
+                    }
+                    Ok(n) => n,
+                    Err(_) => return,
+                };
+                // bytes that could start the detach sequence are held back until it is decided
+                let mut out = Vec::with_capacity(n + keys.len());
+                for &b in &buf[..n] {
+                    if !keys.is_empty() && b == keys[matched] {
+                        matched += 1;
+                        if matched == keys.len() {
+                            detach.store(true, Ordering::SeqCst);
+                            let _ = input.shutdown(Shutdown::Both);
+                            return;
+                        }
+                        continue;
+                    }
+                    out.extend_from_slice(&keys[..matched]);
+                    matched = 0;
+                    if !keys.is_empty() && b == keys[0] {
+                        matched = 1;
+                    } else {
+                        out.push(b);
+                    }
+                }
+                if !out.is_empty() && write_frame(&mut input, FRAME_INPUT, &out).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut buf = [0u8; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => out.write_all(&buf[..n]).and_then(|_| out.flush())
+                .map_err(|e| format!("Write to terminal error {}", e))?,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(_) if detached.load(Ordering::SeqCst) => break,
+            Err(e) => return Err(format!("Read console error {}", e)),
+        }
+    }
+    drop(raw_mode);
+    Ok(detached.load(Ordering::SeqCst))
+}
@@ -1,34 +1,43 @@
 use std::path::{Path, PathBuf};
 use std::fs::File;
-use std::fs::{create_dir_all, remove_dir_all};
+use std::fs::{create_dir_all, remove_dir, remove_dir_all, read_dir, rename};
+use std::io::{Read, Write, Error as IoError};
 use std::ffi::OsStr;
 use std::ffi::CString;
-use std::env::{vars_os, current_dir};
+use std::ptr::null;
+use std::mem::zeroed;
+use std::env;
 use std::os::unix::fs::{PermissionsExt};
 use std::os::unix::process::CommandExt;
-use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{FromRawFd, AsRawFd};
 use std::collections::HashMap;
-use libc::chdir;
-use libc::{pid_t, c_ulong, c_int};
-use unshare::{Command, Stdio, Fd, Child, PipeWriter};
+use libc::{chdir, chroot, umount2, syscall, statfs, SYS_pivot_root, EINVAL, MNT_DETACH, MS_PRIVATE, MS_MOVE};
+use libc::{pid_t, c_ulong, c_int, STDIN_FILENO, sethostname, fork, close, fcntl, flock, F_SETFD, FD_CLOEXEC, LOCK_EX, ENOENT};
+use unshare::{Command, Stdio, Fd, Child, PipeWriter, PipeReader};
+use serde_json;
+use subsystem::ResourceConfig;
+use mountinfo;
+use mountinfo::is_mounted;
+use rootfs::{setup_dev, bind_host_devices, mask_paths, readonly_paths, mount_fs, mount_spec, MountError};
+use security::set_no_new_privs;
+use init_config::{InitConfig, SyncMessage, InitError, read_init_config, write_sync_message, read_sync_message};
+use capabilities::{apply_capabilities, drop_bounding_set, set_keep_caps};
+use user::{resolve_user, switch_user};
+use userns::{IdMappings, in_user_namespace, shift_ownership};
+use config::{info_location, data_root, is_rootless};
+use seccomp::install_profile;
+use restart::RestartPolicy;
+use health::{HealthConfig, HealthState};
+use reaper::{block_signals, prepare_child, reap};
+use tty::{open_pty, set_controlling_terminal};
+use console::Console;
 
 static ref FRIENDLY_STYLE: unshare::Style = unshare::Style::short();
 static ref DEBUG_STYLE: unshare::Style = unshare::Style::debug();
-static MS_NOSUID: c_ulong = 2;                /* Ignore suid and sgid bits.  */
-static MS_NODEV: c_ulong = 4;                 /* Disallow access to device special files.  */
-static MS_NOEXEC: c_ulong = 8;                /* Disallow program execution.  */
 static MS_BIND: c_ulong = 4096;               /* Bind directory at different place.  */
 static MS_REC: c_ulong = 16384;
-static MS_STRICTATIME: c_ulong = 1 << 24;     /* Always perform atime updates.  */
-static MNT_DETACH: c_int = 2;          /* Just detach from the tree.  */
-
-extern {
-    fn mount(source: *const u8, target: *const u8,
-            filesystemtype: *const u8, flags: c_ulong,
-            data: *const u8) -> c_int;
-    fn umount(target: *const u8) -> c_int;
-    fn pivot_root(new_root: *const c_char, put_old: *const c_char) -> c_int;
-}
+const RAMFS_MAGIC: i64 = 0x858458f6;
+const TMPFS_MAGIC: i64 = 0x01021994;
 
 pub fn cmd_show(cmd: &Command) -> unshare::Printer {
     cmd.display(&FRIENDLY_STYLE)
@@ -42,236 +51,546 @@ pub fn cmd_err<E: fmt::Display>(cmd: &Command, err: E) -> String {
    format!("Error running {}: {}", cmd_debug(cmd), err)
 }
 
-pub struct container_info {
-    pid: pid_t,
-    id: u32,
-    name: String,
-    command: String,
-    create_time: String,
-    status: String,
-    volume: String,
-    port_mapping: Vec<u32>
+pub const DEFAULT_INFO_LOCATION: &str = "/var/run/mydocker";
+pub const CONFIG_NAME: &str = "config.json";
+// parent to init: the InitConfig; init to parent: SyncMessages
+const CONFIG_FD: c_int = 3;
+const SYNC_FD: c_int = 4;
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+pub const RUNNING: &str = "running";
+pub const STOP: &str = "stopped";
+pub const PAUSED: &str = "paused";
+pub const EXIT: &str = "exited";
+pub const RESTARTING: &str = "restarting";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ContainerInfo {
+    pub pid: pid_t,
+    pub id: String,
+    pub name: String,
+    pub command: String,
+    pub create_time: String,
+    pub status: String,
+    pub volume: String,
+    pub port_mapping: Vec<String>,
+    #[serde(default)]
+    pub cgroup_path: String,
+    #[serde(default)]
+    pub resources: ResourceConfig,
+    #[serde(default)]
+    pub id_mappings: Option<IdMappings>,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub oom_killed: bool,
+    #[serde(default)]
+    pub finish_time: String,
+    #[serde(default)]
+    pub monitor_pid: pid_t,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub restart_count: u32,
+    #[serde(default)]
+    pub manually_stopped: bool,
+    #[serde(default)]
+    pub healthcheck: Option<HealthConfig>,
+    #[serde(default)]
+    pub health: Option<HealthState>,
+    #[serde(default)]
+    pub auto_remove: bool
+}
+
+fn container_info_path(container_name: &str) -> PathBuf {
+    Path::new(&info_location()).join(container_name).join(CONFIG_NAME)
 }
 
-pub fn new_parent_process(tty: bool,container_name: &str,
-                            volume: &str, image_name: &str,
-                            env_slice: &HashMap<OsStr, OsStr>) -> Result<(&Command, PipeWriter), String> {
+pub fn record_container_info(info: &ContainerInfo) -> Result<(), String> {
+    let dir_url = Path::new(&info_location()).join(&info.name);
+    create_dir_all(&dir_url).map_err(|e| format!("Mkdir error {:?} : {}", dir_url, e))?;
+    let _lock = lock_container_info(&info.name)?;
+    write_container_info(info)
+}
+
+/// Readers never see a partly written file, the new state replaces the old
+/// one in a single rename.
+fn write_container_info(info: &ContainerInfo) -> Result<(), String> {
+    let json = serde_json::to_string(info).map_err(|e| format!("Record container info error {}", e))?;
+    let p = container_info_path(&info.name);
+    let tmp = p.with_extension("json.tmp");
+    let mut f = File::create(&tmp).map_err(|e| format!("Create file {:?} error {}", tmp, e))?;
+    f.write_all(json.as_bytes()).map_err(|e| format!("File write string error {}", e))?;
+    rename(&tmp, &p).map_err(|e| format!("Rename {:?} to {:?} error {}", tmp, p, e))
+}
+
+/// The CLI, the monitor and its health checker all change the state, each
+/// holds this lock from reading it to writing it back. It is taken on the
+/// container's directory, the state file itself is replaced on every write.
+fn lock_container_info(container_name: &str) -> Result<File, String> {
+    let dir_url = Path::new(&info_location()).join(container_name);
+    let dir = File::open(&dir_url).map_err(|e| format!("Open dir {:?} error {}", dir_url, e))?;
+    if unsafe { flock(dir.as_raw_fd(), LOCK_EX) } != 0 {
+        return Err(format!("Lock {:?} error {}", dir_url, IoError::last_os_error()));
+    }
+    Ok(dir)
+}
+
+pub fn get_container_info(container_name: &str) -> Result<ContainerInfo, String> {
+    let p = container_info_path(container_name);
+    let f = File::open(&p).map_err(|e| format!("Read file {:?} error {}", p, e))?;
+    serde_json::from_reader(f).map_err(|e| format!("Json unmarshal error {}", e))
+}
+
+/// Load, modify and write back the persisted state of a container.
+pub fn update_container_info<F>(container_name: &str, update: F) -> Result<ContainerInfo, String>
+    where F: FnOnce(&mut ContainerInfo)
+{
+    let _lock = lock_container_info(container_name)?;
+    let mut info = get_container_info(container_name)?;
+    update(&mut info);
+    write_container_info(&info)?;
+    Ok(info)
+}
+
+pub fn list_container_info() -> Result<Vec<ContainerInfo>, String> {
+    let location = info_location();
+    let entries = read_dir(&location)
+        .map_err(|e| format!("Read dir {} error {}", location, e))?;
+    let mut containers = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Read dir {} error {}", location, e))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        match get_container_info(&name) {
+            Ok(info) => containers.push(info),
+            Err(e) => error!("Get container info error {}", e),
+        }
+    }
+    Ok(containers)
+}
+
+pub fn delete_container_info(container_name: &str) {
+    let dir_url = Path::new(&info_location()).join(container_name);
+    if let Err(e) = remove_dir_all(&dir_url) {
+        error!("Remove dir {:?} error {}", dir_url, e);
+    }
+}
+
+pub fn new_parent_process(tty: bool,container_name: &str, image_name: &str,
+                            id_mappings: Option<&IdMappings>) -> Result<(Child, PipeWriter, PipeReader, Console), String> {
 
     let mut cmd = Command::new("/proc/self/exe");
     cmd.arg("init");
+    // the container's own environment travels in the init config,
+    // init only keeps what it needs for logging
     cmd.env_clear();
-    for (k,v) in env::vars_os() {
-        cmd.env(k, v);
-    }
-    for (k,v) in env_slice {
-        cmd.env(k, v);
+    if let Some(log) = env::var_os("RUST_LOG") {
+        cmd.env("RUST_LOG", log);
     }
+    // init switches its root to whatever directory it starts in
+    cmd.current_dir(format!("{}/mnt/{}", data_root(), container_name));
     cmd.unshare(&[Namespace::Mount, Namespace::Ipc, Namespace::Pid, Namespace::Net, Namespace::Uts]);
+    if let Some(mappings) = id_mappings {
+        mappings.apply(&mut cmd);
+    }
 
-    if !tty {
-        let dir_url = Path::new(format!("/var/run/mydocker/{}/", container_name));
-        set_permissions(&dir_url, Permissions::from_mode(0o622)).map_err(|e| format!("Error setting permissions: {}", e));
-        let f = File::create(dir_url.join("container.log"))?.unwrap();
-        cmd.stdout(Stdio::from_file(f));
+    let (console, stdio) = if tty {
+        // the size is set by whoever attaches
+        Console::from_pty(open_pty()?)?
+    } else {
+        Console::from_pipes()?
+    };
+    let mut fd = 0;
+    for f in stdio {
+        cmd.file_descriptor(fd, Fd::from_file(f));
+        fd += 1;
     }
-    cmd.file_descriptor(3, Fd::piped_write());
+    cmd.file_descriptor(CONFIG_FD, Fd::piped_read());
+    cmd.file_descriptor(SYNC_FD, Fd::piped_write());
     info!("Running {}", cmd_show(&cmd));
-    new_work_space(volume, image_name, container_name);
+    // a rootless user owns its images already, there is nothing to shift
+    let work_space_mappings = if is_rootless() { None } else { id_mappings };
+    new_work_space(image_name, container_name, work_space_mappings)?;
     let mut child = cmd.spawn().map_err(|e| format!("Command {}: {}", cmd_debug(&cmd), e))?;
-    Ok((&cmd, child.take_pipe_writer(3).upwrap()))
-}
-
-fn new_work_space(volume: &str, image_name: &str, container_name: &str) {
-    create_readonly_layer(image_name);
-    create_write_layer(container_name);
-    create_mount_point(container_name, image_name);
-    if volume != "" {
-        let volume_urls: Vec<&str> = volume.split(":").collect();
-        if volume_urls.len() == 2 && volume_urls[0] != "" && volume_urls[1] != "" {
-            mount_volume(volume_urls, container_name);
-            info!("NewWorkSpace volume urls {}", volume_urls);
-        } else {
-            info!("Volume parameter input is not correct.");
+    let config_writer = child.take_pipe_writer(CONFIG_FD).unwrap();
+    let sync_reader = child.take_pipe_reader(SYNC_FD).unwrap();
+    Ok((child, config_writer, sync_reader, console))
+}
+
+fn new_work_space(image_name: &str, container_name: &str,
+                  id_mappings: Option<&IdMappings>) -> Result<(), String> {
+    create_readonly_layer(image_name, id_mappings)?;
+    create_write_layer(container_name, id_mappings)?;
+    create_mount_point(container_name, image_name, id_mappings)?;
+    Ok(())
+}
+
+/// Where an image is unpacked. Remapped containers get their own copy owned
+/// by the mapped ids, like docker's /var/lib/docker/<uid>.<gid>.
+fn image_location(image_name: &str, id_mappings: Option<&IdMappings>) -> Result<String, String> {
+    match id_mappings {
+        Some(mappings) => {
+            let (uid, gid) = mappings.root_pair()?;
+            Ok(format!("{}/{}.{}/{}", data_root(), uid, gid, image_name))
         }
+        None => Ok(format!("{}/{}", data_root(), image_name)),
     }
 }
 
-fn create_readonly_layer(image_name: &str) -> Result<(), String> {
-    let untar_folder = format!("/root/{}", image_name);
+fn create_readonly_layer(image_name: &str, id_mappings: Option<&IdMappings>) -> Result<(), String> {
+    let untar_folder = image_location(image_name, id_mappings)?;
     let untar_folder_path = Path::new(untar_folder);
     set_permissions(&untar_folder_path, Permissions::from_mode(0o622)).map_err(|e| format!("Error setting permissions: {}", e))?;
     if !untar_folder_path.exists() {
         create_dir_all(&untar_folder_path)?;
-        let image_url = format!("/root/{}.tar", image_name);
+        let image_url = format!("{}/{}.tar", data_root(), image_name);
         let mut cmd = Command::new("/proc/self/exe");
         match cmd.args(&["tar", "-xvf", image_url, "-C", untar_folder]).status() {
-            Ok(ref st) if st.success() => Ok(()),
-            Ok(status) => Err(cmd_err(&cmd, status)),
-            Err(err) => Err(cmd_err(&cmd, err)),
+            Ok(ref st) if st.success() => {}
+            Ok(status) => return Err(cmd_err(&cmd, status)),
+            Err(err) => return Err(cmd_err(&cmd, err)),
+        }
+        if let Some(mappings) = id_mappings {
+            shift_ownership(&untar_folder_path, mappings)?;
         }
     }
+    Ok(())
 }
 
-fn create_write_layer(container_name &str) {
-    let write_url = Path::new(format!("/root/writeLayer/{}", container_name));
+fn create_write_layer(container_name &str, id_mappings: Option<&IdMappings>) -> Result<(), String> {
+    let write_url = Path::new(format!("{}/writeLayer/{}", data_root(), container_name));
     set_permissions(&write_url, Permissions::from_mode(0o777)).map_err(|e| format!("Error setting permissions: {}", e))?;
     create_dir_all(&write_url)?;
-}
-
-fn mount_volume(volume_urls: Vec<String>, container_name: String) -> Result<(), String> {
-    let parent_path = Path::new(&volume_urls[0]);
-    set_permissions(&parent_path, Permissions::from_mode(0o777)).map_err(|e| format!("Error setting permissions: {}", e))?;
-    create_dir_all(&parent_path)?;
-
-    let mnt_url = format!("/root/mnt/{}", container_name);
-    let container_volume_url = format!("{}/{}",mnt_url,volume_urls[1]);
-    let container_volume_path = Path::new(&container_volume_url);
-    set_permissions(&container_volume_path, Permissions::from_mode(0o777)).map_err(|e| format!("Error setting permissions: {}", e))?;
-    create_dir_all(&container_volume_path)?;
-
-    let dirs = format!("dirs={}", volume_urls[0]);
-    let mut cmd = Command::new("/proc/self/exe");
-    match cmd.args(&["mount", "-t", "aufs", "-o", dirs, "none", container_volume_url]).status() {
-        Ok(ref st) if st.success() => Ok(()),
-        Ok(status) => Err(cmd_err(&cmd, status)),
-        Err(err) => Err(cmd_err(&cmd, err)),
+    if let Some(mappings) = id_mappings {
+        shift_ownership(&write_url, mappings)?;
     }
+    Ok(())
 }
 
-fn create_mount_point(container_name: String, image_name: String) -> Result<(), String> {
-    let mnt_url = format!("/root/mnt/{}", container_name);
+fn create_mount_point(container_name: String, image_name: String, id_mappings: Option<&IdMappings>) -> Result<(), String> {
+    let mnt_url = format!("{}/mnt/{}", data_root(), container_name);
     let mnt_path = Path::new(mnt_url);
     set_permissions(&mnt_path, Permissions::from_mode(0o777)).map_err(|e| format!("Error setting permissions: {}", e))?;
     create_dir_all(&mnt_path)?;
 
-    let tmp_write_layer = format!("/root/writeLayer/{}", container_name);
-    let tmp_image_location = format!("/root/{}", image_name);
+    if is_mounted(&mnt_url)? {
+        info!("Mount point {} is already mounted", mnt_url);
+        return Ok(());
+    }
+
+    let tmp_write_layer = format!("{}/writeLayer/{}", data_root(), container_name);
+    let tmp_image_location = image_location(&image_name, id_mappings)?;
+    if is_rootless() {
+        return create_rootless_mount_point(&mnt_url, &tmp_write_layer, &tmp_image_location, &container_name);
+    }
     let dirs = format!("dirs={}:{}", tmp_write_layer, tmp_image_location);
     let mut cmd = Command::new("/proc/self/exe");
     match cmd.args(&["mount", "-t", "aufs", "-o", dirs, "none", mnt_url]).status() {
-        Ok(ref st) if st.success() => Ok(()),
+        Ok(ref st) if st.success() => {
+            mountinfo::invalidate();
+            Ok(())
+        }
         Ok(status) => Err(cmd_err(&cmd, status)),
         Err(err) => Err(cmd_err(&cmd, err)),
     }
 }
 
-fn delete_work_space(volume: String, container_name: String) {
-    if volume != "" {
-        let volume_urls: Vec<&str> = volume.split(":").collect();
-        if volume_urls.len() == 2 && volume_urls[0] != "" && volume_urls[1] != "" {
-            delete_volume(volume_urls, container_name);
-        }
+/// An unprivileged user can't mount aufs or overlay on the host, so the root
+/// filesystem comes from fuse-overlayfs, or failing that a plain copy of the image.
+fn create_rootless_mount_point(mnt_url: &str, write_layer: &str, image_location: &str,
+                               container_name: &str) -> Result<(), String> {
+    if let Some(fuse_overlayfs) = env_path_find("fuse-overlayfs") {
+        let work_url = format!("{}/work/{}", data_root(), container_name);
+        create_dir_all(&work_url).map_err(|e| format!("Mkdir {} error {}", work_url, e))?;
+        let opts = format!("lowerdir={},upperdir={},workdir={}", image_location, write_layer, work_url);
+        let mut cmd = Command::new(fuse_overlayfs);
+        return match cmd.args(&["-o", &opts, mnt_url]).status() {
+            Ok(ref st) if st.success() => {
+                mountinfo::invalidate();
+                Ok(())
+            }
+            Ok(status) => Err(cmd_err(&cmd, status)),
+            Err(err) => Err(cmd_err(&cmd, err)),
+        };
     }
+    info!("fuse-overlayfs not found, copying image {} to {}", image_location, mnt_url);
+    let mut cmd = Command::new("cp");
+    match cmd.args(&["-a", &format!("{}/.", image_location), mnt_url]).status() {
+        Ok(ref st) if st.success() => Ok(()),
+        Ok(status) => Err(cmd_err(&cmd, status)),
+        Err(err) => Err(cmd_err(&cmd, err)),
+    }
+}
+
+pub fn delete_work_space(container_name: String) {
     delete_mount_point(container_name);
     delete_write_layer(container_name);
 }
 
 fn delete_mount_point(container_name: String) -> Result<(), String> {
-    let mnt_url = format!("/root/mnt/{}", container_name);
-    let mut cmd = Command::new("/proc/self/exe");
-    cmd.args(&["unmount", mnt_url]).status()?
+    let mnt_url = format!("{}/mnt/{}", data_root(), container_name);
+    if is_mounted(&mnt_url)? {
+        if is_rootless() {
+            let mut cmd = Command::new("fusermount");
+            cmd.args(&["-u", &mnt_url]).status()?
+        } else {
+            let mut cmd = Command::new("/proc/self/exe");
+            cmd.args(&["unmount", mnt_url]).status()?
+        }
+        mountinfo::invalidate();
+    }
+    let work_url = format!("{}/work/{}", data_root(), container_name);
+    if Path::new(&work_url).exists() {
+        remove_dir_all(&work_url)?
+    }
     remove_dir_all(mnt_url)?
     Ok(())
 }
 
-fn delete_volume(volume_urls: &str, container_name: &str) -> Result<(), String> {
-    let mnt_url = format!("/root/mnt/{}", container_name);
-    let container_url = format!("{}/{}", mnt_url, volume_urls[1]);
-    let mut cmd = Command::new("/proc/self/exe");
-    cmd.args(&["unmount", container_url]).status()?
-    Ok(())
-}
-
 fn delete_write_layer(container_name: &str) {
-    let write_url = Path::new(format!("/root/writeLayer/{}", container_name));
+    let write_url = Path::new(format!("{}/writeLayer/{}", data_root(), container_name));
     if let Err(err) = remove_dir_all(mnt_url) {
         info!("Remove writeLayer dir {} error {}", write_url, err);
     }
 }
 
 pub fn run_container_init_process() -> Result<(), String> {
-    let cmd_array: Vec<&str> = read_user_command();
-    setup_mount();
-    if let Some(cmdpath) = env_path_find(cmd_array[0]) {
-        let mut cmd = Command::new(cmdpath);
-        let err = cmd.args(&cmd_array[1..]).exec();
-        if err {
-            Err("failed in exec {}", err);
+    let mut sync = unsafe { File::from_raw_fd(SYNC_FD) };
+    // a successful exec closes the pipe, which is how the parent learns of it
+    if unsafe { fcntl(SYNC_FD, F_SETFD, FD_CLOEXEC) } != 0 {
+        return Err(format!("Can't set close-on-exec on sync pipe: {}", IoError::last_os_error()));
+    }
+    match init_container(&mut sync) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            if let Err(err) = write_sync_message(&mut sync, &SyncMessage::Error(e.clone())) {
+                error!("Report init error to parent failed: {}", err);
+            }
+            Err(e.to_string())
         }
     }
-    Ok(())
 }
 
-fn read_user_command() -> Vec<&str> {
-    let mut buf = vec!();
-    let mut fd3 = unsafe { File::from_raw_fd(3) };
-    match fd3.read_to_end(&mut buf) {
-        Ok(_) => return String::from_utf8(buf).unwrap().split(" ").collect(),
-        Err(e) => {
-            error!("Error reading from fd 3: {}", e);
-            exit(1);
+fn init_container(sync: &mut File) -> Result<(), InitError> {
+    let config = read_init_config(&mut unsafe { File::from_raw_fd(CONFIG_FD) })
+        .map_err(|e| InitError::new("config", e))?;
+    if config.tty {
+        set_controlling_terminal(STDIN_FILENO).map_err(|e| InitError::new("tty", e))?;
+    }
+    let security = &config.security;
+
+    if config.hostname != "" {
+        let c_hostname = CString::new(config.hostname.as_bytes()).unwrap();
+        if unsafe { sethostname(c_hostname.as_ptr(), config.hostname.len()) } != 0 {
+            let errno = IoError::last_os_error().raw_os_error().unwrap_or(0);
+            return Err(InitError::with_errno("hostname", errno, format!("Can't set hostname {}", config.hostname)));
+        }
+    }
+    setup_mount(&config).map_err(|e| InitError::with_errno("mount", e.errno(), e.to_string()))?;
+    for device in &config.devices {
+        device.create_node().map_err(|e| InitError::new("devices", e))?;
+    }
+    let mut cmd = prepare_exec(&config)?;
+    if config.init {
+        let old_mask = block_signals().map_err(|e| InitError::new("init", e))?;
+        match unsafe { fork() } {
+            -1 => {
+                let errno = IoError::last_os_error().raw_os_error().unwrap_or(0);
+                return Err(InitError::with_errno("init", errno, "Can't fork".to_string()));
+            }
+            0 => prepare_child(&old_mask).map_err(|e| InitError::new("init", e))?,
+            child => {
+                // only the command reports back, the parent must see EOF once it has exec'd
+                unsafe { close(sync.as_raw_fd()) };
+                reap(child)
+            }
         }
     }
+    write_sync_message(sync, &SyncMessage::Ready).map_err(|e| InitError::new("sync", e))?;
+    let err = cmd.exec();
+    Err(InitError::with_errno("exec", err.raw_os_error().unwrap_or(0), format!("failed in exec {:?}", config.args)))
 }
 
-fn setup_mount() {
-    match env::current_dir() {
-        Ok(pwd) => root_pivot(pwd),
-        Err(e) => {
-            error!("Get current location error {}", e);
-            return
+/// The steps between the container's filesystem being ready and exec,
+/// shared by init and `mydocker nsenter` so a process entering a running
+/// container is confined just like its command: rlimits, seccomp, the
+/// capability sets, the user, the working directory and the environment.
+pub fn prepare_exec(config: &InitConfig) -> Result<::std::process::Command, InitError> {
+    let security = &config.security;
+    // names resolve against the image's passwd and group files, not the host's
+    let user = if config.user != "" {
+        Some(resolve_user(&config.user).map_err(|e| InitError::new("user", e))?)
+    } else {
+        None
+    };
+    // raising a hard limit needs CAP_SYS_RESOURCE, so before any capability is dropped
+    for rlimit in &config.rlimits {
+        rlimit.apply().map_err(|e| InitError::new("rlimits", e))?;
+    }
+
+    // installed while still holding CAP_SYS_ADMIN, so no_new_privs is only set on request
+    if let Some(ref profile) = security.seccomp {
+        install_profile(profile, security.no_new_privileges).map_err(|e| InitError::new("seccomp", e))?;
+    } else if security.no_new_privileges {
+        set_no_new_privs().map_err(|e| InitError::new("seccomp", e))?;
+    }
+    drop_bounding_set(&security.capabilities).map_err(|e| InitError::new("capabilities", e))?;
+    let mut env = config.env.clone();
+    if let Some(ref user) = user {
+        set_keep_caps(true)
+            .and_then(|_| switch_user(user))
+            .and_then(|_| set_keep_caps(false))
+            .map_err(|e| InitError::new("user", e))?;
+        if !env.iter().any(|e| e.starts_with("HOME=")) {
+            env.push(format!("HOME={}", user.home));
         }
     }
+    // the variables docker always provides, unless -e set them
+    if !env.iter().any(|e| e.starts_with("PATH=")) {
+        env.push(format!("PATH={}", DEFAULT_PATH));
+    }
+    if config.hostname != "" && !env.iter().any(|e| e.starts_with("HOSTNAME=")) {
+        env.push(format!("HOSTNAME={}", config.hostname));
+    }
+    if config.tty && !env.iter().any(|e| e.starts_with("TERM=")) {
+        env.push("TERM=xterm".to_string());
+    }
+    apply_capabilities(&security.capabilities).map_err(|e| InitError::new("capabilities", e))?;
+
+    let cwd = if config.cwd == "" { "/" } else { &config.cwd[..] };
+    env::set_current_dir(cwd).map_err(|e| {
+        InitError::with_errno("cwd", e.raw_os_error().unwrap_or(0), format!("Can't chdir to {}", cwd))
+    })?;
+
+    let path = env.iter().find(|e| e.starts_with("PATH=")).map(|e| e[5..].to_string()).unwrap_or_default();
+    let cmdpath = path_find(&config.args[0], &path)
+        .ok_or(InitError::with_errno("exec", ENOENT, format!("{}: executable file not found in $PATH", config.args[0])))?;
+    let mut cmd = ::std::process::Command::new(cmdpath);
+    cmd.args(&config.args[1..]).env_clear();
+    for e in &env {
+        let mut kv = e.splitn(2, '=');
+        cmd.env(kv.next().unwrap_or(""), kv.next().unwrap_or(""));
+    }
+    Ok(cmd)
+}
 
-    unsafe { mount( "proc".to_cstring().as_bytes().as_ptr(),
-                    "/proc".to_cstring().as_bytes().as_ptr(),
-                    "proc".to_cstring().as_bytes().as_ptr(), MS_NOEXEC | MS_NOSUID | MS_NODEV, null()) };
+/// Block until init has either exec'd the command or reported why it could
+/// not. Call after the init config has been sent and the writer dropped.
+pub fn wait_for_init<R: Read>(sync: &mut R) -> Result<(), String> {
+    let mut ready = false;
+    loop {
+        match read_sync_message(sync)? {
+            Some(SyncMessage::Ready) => ready = true,
+            Some(SyncMessage::Error(e)) => return Err(e.to_string()),
+            Some(SyncMessage::Exit { .. }) => return Err("unexpected exit message from init".to_string()),
+            // the pipe is close-on-exec, EOF after Ready means exec succeeded
+            None if ready => return Ok(()),
+            None => return Err("init exited without reporting a reason".to_string()),
+        }
+    }
+}
+
+fn setup_mount(config: &InitConfig) -> Result<(), MountError> {
+    let security = &config.security;
+    // nothing mounted from here on may propagate back to the host
+    mount_root_private()?;
+    let pwd = env::current_dir().map_err(|e| MountError::io("get current directory", "", e))?;
+
+    // everything is mounted before the root switch, so volumes and the host's
+    // device nodes are still reachable for binding
+    for m in &config.mounts {
+        mount_spec(&pwd, m)?;
+    }
+    // mknod is forbidden in a user namespace
+    if in_user_namespace() {
+        bind_host_devices(&pwd).map_err(|e| MountError::Setup { step: "host devices", message: e })?;
+    }
 
-    unsafe { mount( "tmpfs".to_cstring().as_bytes().as_ptr(),
-                    "/dev".to_cstring().as_bytes().as_ptr(),
-                    "tmpfs".to_cstring().as_bytes().as_ptr(), MS_NOSUID | MS_STRICTATIME, "mode=755".to_cstring().as_bytes().as_ptr()) };
+    root_pivot(&pwd)?;
+
+    setup_dev().map_err(|e| MountError::Setup { step: "/dev", message: e })?;
+    readonly_paths(&security.readonly_paths).map_err(|e| MountError::Setup { step: "readonly paths", message: e })?;
+    mask_paths(&security.masked_paths).map_err(|e| MountError::Setup { step: "masked paths", message: e })?;
+    Ok(())
+}
+
+fn mount_root_private() -> Result<(), MountError> {
+    let c_root = CString::new("/").unwrap();
+    if unsafe { libc::mount(null(), c_root.as_ptr(), null(), MS_PRIVATE | MS_REC, null()) } != 0 {
+        return Err(MountError::syscall("make private", "/"));
+    }
+    Ok(())
 }
 
-fn root_pivot(root &PathBuf) {
+/// Make `root` the container's `/`. pivot_root(2) is refused when the
+/// current root is the initramfs, there the root is moved over `/` instead.
+fn root_pivot(root: &Path) -> Result<(), MountError> {
+    // pivot_root needs the new root to be a mount point of its own
+    let root_str = root.to_string_lossy();
+    mount_fs(&root_str, &root_str, "bind", MS_BIND | MS_REC, None)?;
 
-    let rc = unsafe { mount( root.to_cstring().as_bytes().as_ptr(),
-                root.to_cstring().as_bytes().as_ptr(),
-                "bind".to_cstring().as_bytes().as_ptr(), MS_BIND | MS_REC, null()) };
-    if rc != 0 {
+    let pivot_dir = root.join(".pivot_root");
+    create_dir_all(&pivot_dir).map_err(|e| MountError::io("create", &pivot_dir, e))?;
+
+    let c_root = CString::new(root_str.as_bytes()).unwrap();
+    let c_pivot_dir = CString::new(pivot_dir.to_string_lossy().as_bytes()).unwrap();
+    if unsafe { syscall(SYS_pivot_root, c_root.as_ptr(), c_pivot_dir.as_ptr()) } != 0 {
         let err = IoError::last_os_error();
-        error!("Can't mount {:?} : {}", root, err);
-        return
+        // EINVAL has plenty of other causes, and a chroot can be escaped
+        if err.raw_os_error() != Some(EINVAL) || !root_is_initramfs() {
+            return Err(MountError::Syscall { call: "pivot_root", target: root_str.into_owned(), err: err });
+        }
+        warn!("pivot_root to {:?} refused, moving the root instead", root);
+        remove_dir(&pivot_dir).map_err(|e| MountError::io("remove", &pivot_dir, e))?;
+        return move_root(&c_root, root);
     }
 
-    let mut pivot_dir: PathBuf = root.join("./pivot_root").unwrap();
-    set_permissions(&pivot_dir, Permissions::from_mode(0o777)).map_err(|e| format!("Error setting permissions: {}", e))?;
+    let c_slash = CString::new("/").unwrap();
+    if unsafe { chdir(c_slash.as_ptr()) } != 0 {
+        return Err(MountError::syscall("chdir", "/"));
+    }
 
-    if let Err(e) = create_dir_all(&pivot_dir) {
-        error!("create_dir error {}", e);
-        return
+    let old_root = Path::new("/.pivot_root");
+    let c_old_root = CString::new("/.pivot_root").unwrap();
+    if unsafe { umount2(c_old_root.as_ptr(), MNT_DETACH) } != 0 {
+        return Err(MountError::syscall("unmount", old_root));
     }
+    remove_dir(old_root).map_err(|e| MountError::io("remove", old_root, e))?;
+    Ok(())
+}
 
-    if unsafe { pivot_root(root.to_cstring().as_ptr(), pivot_dir.to_cstring().as_ptr()) } != 0 {
-        error!("Error pivot_root to {:?}: {}", new_root, IoError::last_os_error());
-        return
+/// The initramfs is a ramfs or tmpfs that mountinfo lists as `rootfs`.
+fn root_is_initramfs() -> bool {
+    let c_slash = CString::new("/").unwrap();
+    let mut st: statfs = unsafe { zeroed() };
+    if unsafe { libc::statfs(c_slash.as_ptr(), &mut st) } != 0 {
+        return false;
+    }
+    let fstype = st.f_type as i64;
+    if fstype != RAMFS_MAGIC && fstype != TMPFS_MAGIC {
+        return false;
     }
+    mountinfo::parse_mountinfo(Path::new("/proc/self/mountinfo"))
+        .map(|mounts| mounts.iter().any(|m| m.mount_point == Path::new("/") && m.fstype == "rootfs"))
+        .unwrap_or(false)
+}
 
-    let c_root = CString::new("/").unwrap();
+fn move_root(c_root: &CString, root: &Path) -> Result<(), MountError> {
+    let c_slash = CString::new("/").unwrap();
+    let c_dot = CString::new(".").unwrap();
     if unsafe { chdir(c_root.as_ptr()) } != 0 {
-        error!("Error chdir to root: {}", IoError::last_os_error());
-        return
+        return Err(MountError::syscall("chdir", root));
     }
-
-    pivot_dir = Path::new("/").join(".pivot_root");
-    if unsafe { umount2(pivot_dir.to_cstring().as_bytes().as_ptr(), MNT_DETACH) } != 0 {
-        let err = IoError::last_os_error();
-        error!("Can't unmount {:?} : {}", pivot_dir, err);
-        return
-    };
-    if let Err(err) = remove_dir_all(pivot_dir) {
-        error!("Can't remove_dir {:?} : {}", pivot_dir, err);
-        return
+    if unsafe { libc::mount(c_dot.as_ptr(), c_slash.as_ptr(), null(), MS_MOVE, null()) } != 0 {
+        return Err(MountError::syscall("move mount", root));
     }
+    if unsafe { chroot(c_dot.as_ptr()) } != 0 {
+        return Err(MountError::syscall("chroot", root));
+    }
+    if unsafe { chdir(c_slash.as_ptr()) } != 0 {
+        return Err(MountError::syscall("chdir", "/"));
+    }
+    Ok(())
 }
 
 fn env_path_find<P: AsRef<Path>>(cmd: P) -> Option<PathBuf> {
@@ -0,0 +1,313 @@
+use std::ffi::CString;
+use std::fs::{create_dir_all, metadata, File};
+use std::io::Error as IoError;
+use std::mem::size_of;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use libc::{c_long, chown, major, makedev, minor, mknod, syscall, SYS_bpf, S_IFBLK, S_IFCHR};
+
+/// A device cgroup rule, `major`/`minor` of -1 match any number.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceRule {
+    pub kind: char,
+    pub major: i64,
+    pub minor: i64,
+    pub access: String
+}
+
+impl DeviceRule {
+    fn new(kind: char, major: i64, minor: i64, access: &str) -> DeviceRule {
+        DeviceRule { kind: kind, major: major, minor: minor, access: access.to_string() }
+    }
+
+    /// The rule in `devices.allow` syntax, e.g. `c 1:3 rwm`.
+    pub fn to_cgroup_string(&self) -> String {
+        let num = |n: i64| if n < 0 { "*".to_string() } else { n.to_string() };
+        format!("{} {}:{} {}", self.kind, num(self.major), num(self.minor), self.access)
+    }
+}
+
+/// A host device made available inside the container.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Device {
+    pub path_in_container: String,
+    pub kind: char,
+    pub major: u32,
+    pub minor: u32,
+    pub permissions: String,
+    pub file_mode: u32,
+    pub uid: u32,
+    pub gid: u32
+}
+
+impl Device {
+    /// Parse `--device <host-path>[:<container-path>][:<permissions>]`.
+    pub fn parse(spec: &str) -> Result<Device, String> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let (host, container, permissions) = match parts.len() {
+            1 => (parts[0], parts[0], "rwm"),
+            2 if is_permissions(parts[1]) => (parts[0], parts[0], parts[1]),
+            2 => (parts[0], parts[1], "rwm"),
+            3 => (parts[0], parts[1], parts[2]),
+            _ => return Err(format!("Invalid device specification {:?}", spec)),
+        };
+        if !is_permissions(permissions) {
+            return Err(format!("Invalid device permissions {:?} in {:?}", permissions, spec));
+        }
+        if !Path::new(container).is_absolute() {
+            return Err(format!("Container device path {:?} must be absolute", container));
+        }
+        let meta = metadata(host).map_err(|e| format!("Can't stat device {:?} : {}", host, e))?;
+        let kind = if meta.file_type().is_char_device() {
+            'c'
+        } else if meta.file_type().is_block_device() {
+            'b'
+        } else {
+            return Err(format!("{:?} is not a device node", host));
+        };
+        let rdev = meta.rdev();
+        Ok(Device {
+            path_in_container: container.to_string(),
+            kind: kind,
+            major: major(rdev),
+            minor: minor(rdev),
+            permissions: permissions.to_string(),
+            file_mode: meta.mode() & 0o7777,
+            uid: meta.uid(),
+            gid: meta.gid()
+        })
+    }
+
+    pub fn rule(&self) -> DeviceRule {
+        DeviceRule::new(self.kind, self.major as i64, self.minor as i64, &self.permissions)
+    }
+
+    /// Create the device node; must run inside the container after the root switch.
+    pub fn create_node(&self) -> Result<(), String> {
+        let path = Path::new(&self.path_in_container);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).map_err(|e| format!("Can't create {:?} : {}", parent, e))?;
+        }
+        let kind = if self.kind == 'b' { S_IFBLK } else { S_IFCHR };
+        let c_path = CString::new(self.path_in_container.as_bytes()).unwrap();
+        let dev = makedev(self.major, self.minor);
+        if unsafe { mknod(c_path.as_ptr(), kind | self.file_mode, dev) } != 0 {
+            return Err(format!("Can't mknod {:?} : {}", path, IoError::last_os_error()));
+        }
+        if unsafe { chown(c_path.as_ptr(), self.uid, self.gid) } != 0 {
+            return Err(format!("Can't chown {:?} : {}", path, IoError::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
+fn is_permissions(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 3 && s.chars().all(|c| c == 'r' || c == 'w' || c == 'm')
+}
+
+/// The devices every container may use, matching docker's defaults.
+pub fn default_allow_rules() -> Vec<DeviceRule> {
+    vec![
+        // mknod of any node is allowed, using it still needs an allow rule
+        DeviceRule::new('c', -1, -1, "m"),
+        DeviceRule::new('b', -1, -1, "m"),
+        DeviceRule::new('c', 1, 3, "rwm"),    // /dev/null
+        DeviceRule::new('c', 1, 5, "rwm"),    // /dev/zero
+        DeviceRule::new('c', 1, 7, "rwm"),    // /dev/full
+        DeviceRule::new('c', 1, 8, "rwm"),    // /dev/random
+        DeviceRule::new('c', 1, 9, "rwm"),    // /dev/urandom
+        DeviceRule::new('c', 5, 0, "rwm"),    // /dev/tty
+        DeviceRule::new('c', 5, 1, "rwm"),    // /dev/console
+        DeviceRule::new('c', 5, 2, "rwm"),    // /dev/ptmx
+        DeviceRule::new('c', 136, -1, "rwm"), // /dev/pts/*
+    ]
+}
+
+// eBPF encoding, see include/uapi/linux/bpf.h and bpf_common.h
+const BPF_PROG_LOAD: c_long = 5;
+const BPF_PROG_ATTACH: c_long = 8;
+const BPF_PROG_TYPE_CGROUP_DEVICE: u32 = 15;
+const BPF_CGROUP_DEVICE: u32 = 6;
+const BPF_F_ALLOW_MULTI: u32 = 2;
+
+const BPF_DEVCG_DEV_BLOCK: i32 = 1;
+const BPF_DEVCG_DEV_CHAR: i32 = 2;
+const BPF_DEVCG_ACC_MKNOD: i32 = 1;
+const BPF_DEVCG_ACC_READ: i32 = 2;
+const BPF_DEVCG_ACC_WRITE: i32 = 4;
+
+const LDX_MEM_W: u8 = 0x61;
+const ALU64_AND_K: u8 = 0x57;
+const ALU64_RSH_K: u8 = 0x77;
+const ALU64_MOV_K: u8 = 0xb7;
+const ALU64_MOV_X: u8 = 0xbf;
+const JMP_JNE_K: u8 = 0x55;
+const JMP_JNE_X: u8 = 0x5d;
+const JMP_EXIT: u8 = 0x95;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BpfInsn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32
+}
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> BpfInsn {
+    BpfInsn { code: code, regs: (src << 4) | dst, off: off, imm: imm }
+}
+
+/// Compile the rules into a BPF_PROG_TYPE_CGROUP_DEVICE program that returns 1
+/// for an allowed access and 0 otherwise.
+fn compile_device_filter(rules: &[DeviceRule]) -> Vec<BpfInsn> {
+    // struct bpf_cgroup_dev_ctx { u32 access_type; u32 major; u32 minor; }
+    let mut prog = vec![
+        insn(LDX_MEM_W, 2, 1, 0, 0),
+        insn(ALU64_MOV_X, 3, 2, 0, 0),
+        insn(ALU64_AND_K, 3, 0, 0, 0xffff), // r3 = device type
+        insn(ALU64_MOV_X, 4, 2, 0, 0),
+        insn(ALU64_RSH_K, 4, 0, 0, 16),     // r4 = requested access
+        insn(LDX_MEM_W, 5, 1, 4, 0),        // r5 = major
+        insn(LDX_MEM_W, 6, 1, 8, 0),        // r6 = minor
+    ];
+    for rule in rules {
+        let mut block = Vec::new();
+        match rule.kind {
+            'c' => block.push(insn(JMP_JNE_K, 3, 0, 0, BPF_DEVCG_DEV_CHAR)),
+            'b' => block.push(insn(JMP_JNE_K, 3, 0, 0, BPF_DEVCG_DEV_BLOCK)),
+            _ => {}
+        }
+        let mut access = 0;
+        for c in rule.access.chars() {
+            access |= match c {
+                'r' => BPF_DEVCG_ACC_READ,
+                'w' => BPF_DEVCG_ACC_WRITE,
+                'm' => BPF_DEVCG_ACC_MKNOD,
+                _ => 0,
+            };
+        }
+        // the requested access must be a subset of the allowed one
+        block.push(insn(ALU64_MOV_X, 7, 4, 0, 0));
+        block.push(insn(ALU64_AND_K, 7, 0, 0, access));
+        block.push(insn(JMP_JNE_X, 7, 4, 0, 0));
+        if rule.major >= 0 {
+            block.push(insn(JMP_JNE_K, 5, 0, 0, rule.major as i32));
+        }
+        if rule.minor >= 0 {
+            block.push(insn(JMP_JNE_K, 6, 0, 0, rule.minor as i32));
+        }
+        block.push(insn(ALU64_MOV_K, 0, 0, 0, 1));
+        block.push(insn(JMP_EXIT, 0, 0, 0, 0));
+        // every jump in the block skips to the next rule
+        let len = block.len();
+        for (i, ins) in block.iter_mut().enumerate() {
+            if ins.code == JMP_JNE_K || ins.code == JMP_JNE_X {
+                ins.off = (len - i - 1) as i16;
+            }
+        }
+        prog.extend(block);
+    }
+    prog.push(insn(ALU64_MOV_K, 0, 0, 0, 0));
+    prog.push(insn(JMP_EXIT, 0, 0, 0, 0));
+    prog
+}
+
+#[repr(C)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    _pad: [u8; 80]
+}
+
+#[repr(C)]
+struct BpfProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+    _pad: [u8; 112]
+}
+
+/// Load the device filter and attach it to the cgroup v2 directory `cgroup_dir`.
+pub fn attach_device_filter(cgroup_dir: &Path, rules: &[DeviceRule]) -> Result<(), String> {
+    let prog = compile_device_filter(rules);
+    let license = CString::new("Apache").unwrap();
+    let mut log = vec![0u8; 4096];
+    let load = BpfProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_CGROUP_DEVICE,
+        insn_cnt: prog.len() as u32,
+        insns: prog.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 1,
+        log_size: log.len() as u32,
+        log_buf: log.as_mut_ptr() as u64,
+        kern_version: 0,
+        prog_flags: 0,
+        _pad: [0; 80]
+    };
+    let prog_fd = unsafe { syscall(SYS_bpf, BPF_PROG_LOAD, &load as *const _, size_of::<BpfProgLoadAttr>()) };
+    if prog_fd < 0 {
+        let verifier = String::from_utf8_lossy(&log);
+        return Err(format!("Can't load device filter : {} {}",
+                           IoError::last_os_error(), verifier.trim_end_matches('\0')));
+    }
+
+    let dir = File::open(cgroup_dir).map_err(|e| format!("Can't open cgroup {:?} : {}", cgroup_dir, e))?;
+    let attach = BpfProgAttachAttr {
+        target_fd: dir.as_raw_fd() as u32,
+        attach_bpf_fd: prog_fd as u32,
+        attach_type: BPF_CGROUP_DEVICE,
+        attach_flags: BPF_F_ALLOW_MULTI,
+        _pad: [0; 112]
+    };
+    let rc = unsafe { syscall(SYS_bpf, BPF_PROG_ATTACH, &attach as *const _, size_of::<BpfProgAttachAttr>()) };
+    // the attachment keeps the program alive, our fd is no longer needed
+    unsafe { ::libc::close(prog_fd as i32) };
+    if rc != 0 {
+        return Err(format!("Can't attach device filter to {:?} : {}", cgroup_dir, IoError::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_device_forms() {
+        let d = Device::parse("/dev/null").unwrap();
+        assert_eq!((d.kind, d.major, d.minor), ('c', 1, 3));
+        assert_eq!(d.path_in_container, "/dev/null");
+        assert_eq!(d.permissions, "rwm");
+        assert_eq!(d.rule().to_cgroup_string(), "c 1:3 rwm");
+
+        let d = Device::parse("/dev/null:/dev/mynull").unwrap();
+        assert_eq!((&d.path_in_container[..], &d.permissions[..]), ("/dev/mynull", "rwm"));
+        let d = Device::parse("/dev/null:r").unwrap();
+        assert_eq!((&d.path_in_container[..], &d.permissions[..]), ("/dev/null", "r"));
+        let d = Device::parse("/dev/null:/dev/mynull:rw").unwrap();
+        assert_eq!((&d.path_in_container[..], &d.permissions[..]), ("/dev/mynull", "rw"));
+    }
+
+    #[test]
+    fn invalid_devices() {
+        for spec in &["/dev/null:relative", "/dev/null:/dev/x:rwx", "/dev/null:/dev/x:rw:m", "/", "/no/such/device"] {
+            assert!(Device::parse(spec).is_err(), "{} was accepted", spec);
+        }
+    }
+
+    #[test]
+    fn wildcard_rules() {
+        assert_eq!(DeviceRule::new('c', 136, -1, "rwm").to_cgroup_string(), "c 136:* rwm");
+        assert_eq!(DeviceRule::new('a', -1, -1, "rwm").to_cgroup_string(), "a *:* rwm");
+    }
+}
@@ -0,0 +1,251 @@
+use std::fs::File;
+use std::io::{Read, Error as IoError};
+use std::os::unix::io::FromRawFd;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+use libc::{pid_t, kill, pipe2, O_CLOEXEC, SIGKILL};
+use container::update_container_info;
+use init_config::{InitConfig, write_init_config};
+use now;
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_RETRIES: u32 = 3;
+
+pub const STARTING: &str = "starting";
+pub const HEALTHY: &str = "healthy";
+pub const UNHEALTHY: &str = "unhealthy";
+
+// like docker, only the latest results and the start of their output are kept
+const MAX_LOG_ENTRIES: usize = 5;
+const MAX_OUTPUT: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthConfig {
+    /// Run with `/bin/sh -c` inside the container.
+    pub cmd: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Consecutive failures before the container is unhealthy.
+    pub retries: u32,
+    /// Failures during this time after a start don't count.
+    pub start_period: Duration,
+    /// Kill an unhealthy container so its monitor starts it again.
+    pub restart_unhealthy: bool
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthState {
+    pub status: String,
+    pub failing_streak: u32,
+    pub log: Vec<HealthResult>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthResult {
+    pub start: String,
+    pub end: String,
+    /// -1 when the check timed out or couldn't run at all.
+    pub exit_code: i32,
+    pub output: String
+}
+
+/// Parse `500ms`, `30s`, `1m30s` or `1h`. A bare number is seconds.
+pub fn parse_duration(spec: &str) -> Result<Duration, String> {
+    if spec == "" {
+        return Err("Empty duration".to_string());
+    }
+    if let Ok(secs) = spec.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    let mut total = Duration::from_secs(0);
+    let mut rest = spec;
+    while rest != "" {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let value: u64 = rest[..digits].parse().map_err(|_| format!("Invalid duration {}", spec))?;
+        rest = &rest[digits..];
+        let unit = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        total += match &rest[..unit] {
+            "ms" => Duration::from_millis(value),
+            "s" => Duration::from_secs(value),
+            "m" => Duration::from_secs(value * 60),
+            "h" => Duration::from_secs(value * 3600),
+            _ => return Err(format!("Invalid duration {}", spec)),
+        };
+        rest = &rest[unit..];
+    }
+    Ok(total)
+}
+
+impl HealthState {
+    pub fn starting() -> HealthState {
+        HealthState { status: STARTING.to_string(), failing_streak: 0, log: Vec::new() }
+    }
+
+    /// Add a result, returns true when it turned the container unhealthy.
+    fn record(&mut self, result: HealthResult, config: &HealthConfig, in_start_period: bool) -> bool {
+        let passed = result.exit_code == 0;
+        self.log.push(result);
+        if self.log.len() > MAX_LOG_ENTRIES {
+            self.log.remove(0);
+        }
+        if passed {
+            self.status = HEALTHY.to_string();
+            self.failing_streak = 0;
+            return false;
+        }
+        // a slow starter isn't held against the container, until it passed once
+        if in_start_period && self.status == STARTING {
+            return false;
+        }
+        self.failing_streak += 1;
+        if self.failing_streak >= config.retries && self.status != UNHEALTHY {
+            self.status = UNHEALTHY.to_string();
+            return true;
+        }
+        false
+    }
+}
+
+/// Checks a running container from a thread of its monitor.
+pub struct HealthChecker {
+    stop: Arc<AtomicBool>,
+    killed_unhealthy: Arc<AtomicBool>,
+    handle: JoinHandle<()>
+}
+
+impl HealthChecker {
+    /// `init` is the container command's config, the check runs with the
+    /// same user, environment and confinement.
+    pub fn spawn(name: &str, pid: pid_t, config: HealthConfig, init: &InitConfig) -> HealthChecker {
+        let mut exec = init.clone();
+        exec.args = vec!["/bin/sh".to_string(), "-c".to_string(), config.cmd.clone()];
+        exec.tty = false;
+        exec.init = false;
+        let stop = Arc::new(AtomicBool::new(false));
+        let killed_unhealthy = Arc::new(AtomicBool::new(false));
+        let (name, thread_stop, thread_killed) = (name.to_string(), stop.clone(), killed_unhealthy.clone());
+        let handle = thread::spawn(move || {
+            let started = Instant::now();
+            loop {
+                if sleep_unless_stopped(&thread_stop, config.interval) {
+                    return;
+                }
+                let result = run_check(&config, pid, &exec);
+                if thread_stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                let in_start_period = started.elapsed() < config.start_period;
+                let mut turned_unhealthy = false;
+                if let Err(e) = update_container_info(&name, |info| {
+                    let health = info.health.get_or_insert_with(HealthState::starting);
+                    turned_unhealthy = health.record(result, &config, in_start_period);
+                }) {
+                    error!("Record health of {} error {}", name, e);
+                }
+                if turned_unhealthy {
+                    warn!("Container {} is unhealthy", name);
+                    if config.restart_unhealthy {
+                        thread_killed.store(true, Ordering::SeqCst);
+                        unsafe { kill(pid, SIGKILL) };
+                        return;
+                    }
+                }
+            }
+        });
+        HealthChecker { stop: stop, killed_unhealthy: killed_unhealthy, handle: handle }
+    }
+
+    /// Stop checking once the container exited. Returns true if the
+    /// checker killed it for being unhealthy.
+    pub fn stop(self) -> bool {
+        self.stop.store(true, Ordering::SeqCst);
+        if self.handle.join().is_err() {
+            error!("Health checker panicked");
+        }
+        self.killed_unhealthy.load(Ordering::SeqCst)
+    }
+}
+
+fn sleep_unless_stopped(stop: &AtomicBool, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if stop.load(Ordering::SeqCst) {
+            return true;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    stop.load(Ordering::SeqCst)
+}
+
+/// Run the check through `mydocker nsenter`, a fresh single threaded process
+/// that can join the container's namespaces.
+fn run_check(config: &HealthConfig, pid: pid_t, exec: &InitConfig) -> HealthResult {
+    let start = now();
+    let (exit_code, output) = match exec_check(config, pid, exec) {
+        Ok(result) => result,
+        Err(e) => (-1, e),
+    };
+    HealthResult { start: start, end: now(), exit_code: exit_code, output: output }
+}
+
+fn exec_check(config: &HealthConfig, pid: pid_t, exec: &InitConfig) -> Result<(i32, String), String> {
+    let mut fds = [0; 2];
+    if unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) } != 0 {
+        return Err(format!("Can't create pipe : {}", IoError::last_os_error()));
+    }
+    let (mut reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    let stderr = writer.try_clone().map_err(|e| format!("Clone pipe error {}", e))?;
+
+    // the environment travels in the config, nsenter's own is the monitor's
+    let mut cmd = Command::new("/proc/self/exe");
+    cmd.arg("nsenter").arg("--target").arg(pid.to_string());
+    cmd.stdin(Stdio::piped()).stdout(Stdio::from(writer)).stderr(Stdio::from(stderr));
+    let mut child = cmd.spawn().map_err(|e| format!("Run health check error {}", e))?;
+    // our copies of the write end must go, or the output never ends
+    drop(cmd);
+    let sent = match child.stdin.take() {
+        Some(mut stdin) => write_init_config(&mut stdin, exec),
+        None => Err("No stdin to send the health check config".to_string()),
+    };
+    if let Err(e) = sent {
+        child.kill().ok();
+        child.wait().ok();
+        return Err(e);
+    }
+
+    let output = thread::spawn(move || {
+        let mut output = Vec::new();
+        let mut buf = [0u8; 4096];
+        // keep draining past the limit so the check never blocks on a full pipe
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            let keep = n.min(MAX_OUTPUT.saturating_sub(output.len()));
+            output.extend_from_slice(&buf[..keep]);
+        }
+        String::from_utf8_lossy(&output).into_owned()
+    });
+
+    let deadline = Instant::now() + config.timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                let output = output.join().unwrap_or_default();
+                return Ok((status.code().unwrap_or(-1), output));
+            }
+            Ok(None) if Instant::now() >= deadline => {
+                // the command inside dies with nsenter
+                child.kill().ok();
+                child.wait().ok();
+                return Ok((-1, format!("Health check exceeded timeout ({:?})", config.timeout)));
+            }
+            Ok(None) => sleep(Duration::from_millis(50)),
+            Err(e) => return Err(format!("Wait health check error {}", e)),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt;
+use std::io::{Read, Write, ErrorKind, Error as IoError};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use libc::{c_int, rlimit, setrlimit};
+use libc::{RLIMIT_AS, RLIMIT_CORE, RLIMIT_CPU, RLIMIT_DATA, RLIMIT_FSIZE, RLIMIT_LOCKS, RLIMIT_MEMLOCK,
+           RLIMIT_MSGQUEUE, RLIMIT_NICE, RLIMIT_NOFILE, RLIMIT_NPROC, RLIMIT_RSS, RLIMIT_RTPRIO,
+           RLIMIT_RTTIME, RLIMIT_SIGPENDING, RLIMIT_STACK};
+use devices::Device;
+use security::SecurityOptions;
+
+/// Bumped whenever `InitConfig` changes incompatibly, so a stale binary
+/// fails loudly instead of misreading the message.
+pub const INIT_PROTOCOL_VERSION: u32 = 2;
+
+// far beyond any sane config, guards against reading garbage as a length
+const MAX_MESSAGE_SIZE: u32 = 16 << 20;
+
+/// Everything the container init needs, sent by the parent over fd 3.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InitConfig {
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+    pub cwd: String,
+    pub user: String,
+    pub hostname: String,
+    /// Mounted below the new root, in order, before switching to it.
+    pub mounts: Vec<MountSpec>,
+    pub rlimits: Vec<Rlimit>,
+    pub devices: Vec<Device>,
+    pub security: SecurityOptions,
+    /// Keep PID 1 for a reaper and run the command as its child.
+    pub init: bool,
+    /// Stdio is a pty slave that should become the controlling terminal.
+    pub tty: bool
+}
+
+/// A filesystem to mount into the container. `options` hold mount flags
+/// such as `rbind`, `ro` or `nosuid`, anything else is passed as data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MountSpec {
+    pub source: String,
+    pub destination: String,
+    pub fstype: String,
+    pub options: Vec<String>
+}
+
+impl MountSpec {
+    /// Parse `-v <host-dir>:<container-dir>[:ro]` into a bind mount.
+    pub fn volume(spec: &str) -> Result<MountSpec, String> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let readonly = match parts.get(2) {
+            None => false,
+            Some(&"ro") => true,
+            Some(&"rw") => false,
+            Some(mode) => return Err(format!("Invalid volume mode {:?} in {:?}", mode, spec)),
+        };
+        if parts.len() > 3 || parts[0] == "" || parts.get(1).map(|d| *d == "").unwrap_or(true) {
+            return Err(format!("Invalid volume {:?}, expected <host-dir>:<container-dir>[:ro]", spec));
+        }
+        if !parts[1].starts_with('/') {
+            return Err(format!("Volume destination {:?} must be an absolute path", parts[1]));
+        }
+        let mut options = vec!["rbind".to_string()];
+        if readonly {
+            options.push("ro".to_string());
+        }
+        Ok(MountSpec { source: parts[0].to_string(), destination: parts[1].to_string(), fstype: "bind".to_string(), options: options })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rlimit {
+    pub name: String,
+    pub soft: u64,
+    pub hard: u64
+}
+
+const RLIMITS: [(&str, c_int); 16] = [
+    ("core", RLIMIT_CORE as c_int), ("cpu", RLIMIT_CPU as c_int), ("data", RLIMIT_DATA as c_int),
+    ("fsize", RLIMIT_FSIZE as c_int), ("locks", RLIMIT_LOCKS as c_int),
+    ("memlock", RLIMIT_MEMLOCK as c_int), ("msgqueue", RLIMIT_MSGQUEUE as c_int),
+    ("nice", RLIMIT_NICE as c_int), ("nofile", RLIMIT_NOFILE as c_int), ("nproc", RLIMIT_NPROC as c_int),
+    ("rss", RLIMIT_RSS as c_int), ("rtprio", RLIMIT_RTPRIO as c_int), ("rttime", RLIMIT_RTTIME as c_int),
+    ("sigpending", RLIMIT_SIGPENDING as c_int), ("stack", RLIMIT_STACK as c_int), ("as", RLIMIT_AS as c_int),
+];
+
+impl Rlimit {
+    /// Parse `--ulimit <name>=<soft>[:<hard>]`, e.g. `nofile=1024:2048`.
+    pub fn parse(spec: &str) -> Result<Rlimit, String> {
+        let mut kv = spec.splitn(2, '=');
+        let name = kv.next().unwrap_or("").to_string();
+        if !RLIMITS.iter().any(|&(n, _)| n == name) {
+            return Err(format!("Invalid ulimit type {:?}", name));
+        }
+        let value = kv.next().ok_or(format!("Invalid ulimit {:?}, expected <name>=<soft>[:<hard>]", spec))?;
+        let mut limits = value.splitn(2, ':');
+        let parse = |v: &str| v.parse::<i64>()
+            .map(|v| if v < 0 { u64::max_value() } else { v as u64 })
+            .map_err(|e| format!("Invalid ulimit value {:?} : {}", v, e));
+        let soft = parse(limits.next().unwrap_or(""))?;
+        let hard = match limits.next() {
+            Some(v) => parse(v)?,
+            None => soft,
+        };
+        if soft > hard {
+            return Err(format!("Ulimit soft limit must be less than or equal to hard limit: {} > {}", soft, hard));
+        }
+        Ok(Rlimit { name: name, soft: soft, hard: hard })
+    }
+
+    pub fn apply(&self) -> Result<(), String> {
+        let resource = RLIMITS.iter().find(|&&(n, _)| n == self.name)
+            .map(|&(_, r)| r).ok_or(format!("Invalid ulimit type {:?}", self.name))?;
+        let limit = rlimit { rlim_cur: self.soft as _, rlim_max: self.hard as _ };
+        if unsafe { setrlimit(resource as _, &limit) } != 0 {
+            return Err(format!("Can't set rlimit {} : {}", self.name, ::std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
+/// Frame: protocol version and payload length as big-endian u32, then JSON.
+pub fn write_init_config<W: Write>(w: &mut W, config: &InitConfig) -> Result<(), String> {
+    write_message(w, config).map_err(|e| format!("Write init config error {}", e))
+}
+
+pub fn read_init_config<R: Read>(r: &mut R) -> Result<InitConfig, String> {
+    let config: InitConfig = match read_message(r) {
+        Ok(Some(config)) => config,
+        Ok(None) => return Err("Read init config error: pipe closed".to_string()),
+        Err(e) => return Err(format!("Read init config error {}", e)),
+    };
+    if config.args.is_empty() || config.args[0].is_empty() {
+        return Err("No command specified".to_string());
+    }
+    Ok(config)
+}
+
+/// What init reports back to `run` over the sync pipe on fd 4. The pipe is
+/// close-on-exec, so EOF after `Ready` means the command is running.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SyncMessage {
+    Ready,
+    Error(InitError),
+    /// Only from the monitor: the exit code of the container's first run.
+    Exit { code: i32 },
+}
+
+/// Why init gave up, and at which step.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InitError {
+    pub stage: String,
+    pub errno: i32,
+    pub message: String
+}
+
+impl InitError {
+    /// A failure that didn't come straight from a syscall.
+    pub fn new(stage: &str, message: String) -> InitError {
+        InitError::with_errno(stage, 0, message)
+    }
+
+    /// A failed syscall, `errno` is shown after the message.
+    pub fn with_errno(stage: &str, errno: i32, message: String) -> InitError {
+        InitError { stage: stage.to_string(), errno: errno, message: message }
+    }
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.stage, self.message)?;
+        if self.errno != 0 {
+            write!(f, " ({})", IoError::from_raw_os_error(self.errno))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn write_sync_message<W: Write>(w: &mut W, msg: &SyncMessage) -> Result<(), String> {
+    write_message(w, msg).map_err(|e| format!("Write sync message error {}", e))
+}
+
+/// `None` once init closed its end without saying anything more.
+pub fn read_sync_message<R: Read>(r: &mut R) -> Result<Option<SyncMessage>, String> {
+    read_message(r).map_err(|e| format!("Read sync message error {}", e))
+}
+
+// messages are an 8 byte header, protocol version and payload length both
+// big endian u32, followed by the JSON payload
+fn write_message<W: Write, T: Serialize>(w: &mut W, msg: &T) -> Result<(), String> {
+    let payload = serde_json::to_vec(msg).map_err(|e| format!("encode: {}", e))?;
+    let mut header = [0u8; 8];
+    header[..4].copy_from_slice(&INIT_PROTOCOL_VERSION.to_be_bytes());
+    header[4..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    w.write_all(&header).and_then(|_| w.write_all(&payload)).and_then(|_| w.flush())
+        .map_err(|e| e.to_string())
+}
+
+fn read_message<R: Read, T: DeserializeOwned>(r: &mut R) -> Result<Option<T>, String> {
+    let mut header = [0u8; 8];
+    let mut read = 0;
+    while read < header.len() {
+        match r.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err("truncated header".to_string()),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&header[..4]);
+    let version = u32::from_be_bytes(word);
+    if version != INIT_PROTOCOL_VERSION {
+        return Err(format!("protocol version mismatch: got {}, expected {}", version, INIT_PROTOCOL_VERSION));
+    }
+    word.copy_from_slice(&header[4..]);
+    let len = u32::from_be_bytes(word);
+    if len > MAX_MESSAGE_SIZE {
+        return Err(format!("message of {} bytes is too large", len));
+    }
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload).map_err(|e| e.to_string())?;
+    serde_json::from_slice(&payload).map(Some).map_err(|e| format!("decode: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume_is_a_bind_mount() {
+        let m = MountSpec::volume("/srv/data:/data").unwrap();
+        assert_eq!(m, MountSpec {
+            source: "/srv/data".to_string(),
+            destination: "/data".to_string(),
+            fstype: "bind".to_string(),
+            options: vec!["rbind".to_string()],
+        });
+        assert_eq!(MountSpec::volume("/srv/data:/data:ro").unwrap().options, vec!["rbind", "ro"]);
+        assert_eq!(MountSpec::volume("/srv/data:/data:rw").unwrap().options, vec!["rbind"]);
+    }
+
+    #[test]
+    fn invalid_volumes() {
+        for spec in &["/srv/data", ":/data", "/srv/data:", "/srv/data:data", "/srv/data:/data:rx", "/a:/b:ro:x"] {
+            assert!(MountSpec::volume(spec).is_err(), "{} was accepted", spec);
+        }
+    }
+}
@@ -1,25 +1,75 @@
 use std::env;
+use std::fs::create_dir_all;
+use std::collections::HashMap;
+use std::path::Path;
 use std::process::exit;
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // use libc::{getuid, kill, c_int, pid_t};
-use subsystem::ResourceConfig;
-use container::{new_parent_process, delete_work_space, run_container_init_process};
-use cgroup::CgroupManager;
+use subsystem::{ResourceConfig, parse_size};
+use rootfs::{DEFAULT_SHM_SIZE, default_mounts};
+use security::SecurityOptions;
+use capabilities::resolve_capabilities;
+use config::{DaemonConfig, is_rootless};
+use userns::IdMappings;
+use container::run_container_init_process;
+use container::{ContainerInfo, get_container_info, update_container_info, list_container_info};
+use container::{RUNNING, PAUSED, RESTARTING, EXIT};
+use cgroup::{CgroupManager, delegated_cgroup_root};
+use devices::Device;
+use init_config::{InitConfig, MountSpec, Rlimit, read_init_config};
+use shim::{ShimConfig, start_shim};
+use restart::RestartPolicy;
+use health::{HealthConfig, parse_duration, DEFAULT_INTERVAL, DEFAULT_TIMEOUT, DEFAULT_RETRIES};
+use nsenter::exec_in_container;
+use stats::{ContainerStats, read_network_stats, human_size};
+use console::{attach, parse_detach_keys, DEFAULT_DETACH_KEYS};
 
 extern crate env_logger;
 extern crate argparse;
 extern crate libc;
 extern crate env_logger;
 extern crate rand;
+#[macro_use] extern crate lazy_static;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
 
 // #[macro_use] extern crate quick_error;
 #[macro_use] extern crate log;
 #[cfg(feature="containers")] extern crate unshare;
 
-use argparse::{ArgumentParser, StoreFalse, Store};
+use argparse::{ArgumentParser, StoreFalse, StoreTrue, Store, StoreOption, Collect, List};
 use rand::{thread_rng, Rng};
 use unshare::{Command, Stdio, Fd, Child, PipeWriter};
 
+mod capabilities;
+mod cgroup;
+mod config;
+mod console;
+mod container;
+mod devices;
+mod health;
+mod init_config;
+mod mountinfo;
+mod nsenter;
+mod oom;
+mod reaper;
+mod restart;
+mod rootfs;
+mod seccomp;
+mod security;
+mod shim;
+mod stats;
+mod subsystem;
+mod syscalls;
+mod tty;
+mod user;
+mod userns;
+
+const DEFAULT_CGROUP_PARENT: &str = "mydocker";
+
 #[cfg(feature="containers")]
 fn main() {
     env_logger::init();
@@ -30,7 +80,15 @@ fn main() {
     let code = match &ep[..] {
         "run" => run(args[2..]),
         "init" => init_process(args[2..]),
-        // "stop" => stop_command(args[2..]),
+        "ps" => list_containers(),
+        "inspect" => inspect_container(args[2..]),
+        "stats" => stats_command(args[2..]),
+        "pause" => pause_container(args[2..]),
+        "unpause" => unpause_container(args[2..]),
+        "attach" => attach_container(args[2..]),
+        "update" => update_container(args[2..]),
+        "stop" => stop_command(args[2..]),
+        "nsenter" => nsenter_command(args[2..]),
         // "exec" => exec_command(args[2..]),
         // "rm" => remove_command(args[2..]),
         // "commit" => commit_command(args[2..]),
@@ -40,112 +98,765 @@ fn main() {
     exit(code);
 }
 
-fn init_process(input_args: Vec<String>) {
+fn init_process(input_args: Vec<String>) -> i32 {
     match run_container_init_process() {
         Ok(_) => {
             info!("parent process init ok");
-            return
+            0
         }
         Err(e) => {
             error!("parent process init failed: {}", e);
-            return
+            1
         }
     }
 }
 
-// fn stop_command(input_args: Vec<String>) {
-//     if input_args.len() < 1 {
-//         error!("Missing container command");
-//         return;
-//     }
-//     let container_name = input_args[0];
-//     stop_container(container_name);
-// }
-//
-// fn stop_container(container_name: String) {
-//
-// }
-
-// pub fn send_signal(sig: Signal, pid: pid_t, cmd_name: &String) {
-//     if unsafe { kill(pid, sig as c_int) } < 0 {
-//         let e = io::Error::last_os_error();
-//         error!("Error sending {:?} to {:?}: {}", sig, cmd_name, e);
-//     }
-// }
-
-fn run(input_args: Vec<String>) {
+fn run(input_args: Vec<String>) -> i32 {
     let mut create_tty = false;
+    let mut interactive = false;
     let mut detach = false;
-    let mut res_conf = ResourceConfig{};
+    let mut detach_keys = DEFAULT_DETACH_KEYS.to_string();
+    let mut res_conf = ResourceConfig::default();
+    let mut oom_kill_disable = false;
     let mut container_name: String;
+    let mut cgroup_parent = DEFAULT_CGROUP_PARENT.to_string();
+    let mut shm_size = String::new();
+    let mut security_opts: Vec<String> = Vec::new();
+    let mut cap_add: Vec<String> = Vec::new();
+    let mut cap_drop: Vec<String> = Vec::new();
+    let mut privileged = false;
+    let mut init = false;
+    let mut auto_remove = false;
+    let mut restart = String::new();
+    let mut health_cmd = String::new();
+    let mut health_interval = String::new();
+    let mut health_timeout = String::new();
+    let mut health_retries = DEFAULT_RETRIES;
+    let mut health_start_period = String::new();
+    let mut restart_unhealthy = false;
+    let mut userns_remap = String::new();
+    let mut user = String::new();
     let mut volume: String;
     let mut network: String;
-    let mut env_slice: String;
+    let mut env_vars: Vec<String> = Vec::new();
+    let mut hostname = String::new();
+    let mut workdir = "/".to_string();
+    let mut ulimits: Vec<String> = Vec::new();
     let mut portmapping: String;
 
     let mut image_name: String;
-    let mut cname: String;
+    let mut args: Vec<String> = Vec::new();
 
-    if input_args.len() < 1 {
+    {
+        let mut ap = ArgumentParser::new();
+        ap.refer(&mut create_tty).add_option(&["-t", "--tty"], StoreTrue, "allocate a pseudo-TTY");
+        ap.refer(&mut interactive).add_option(&["-i", "--interactive"], StoreTrue, "keep stdin open");
+        ap.refer(&mut detach).add_option(&["-d"], StoreTrue, "run the container in the background");
+        ap.refer(&mut detach_keys).add_option(&["--detach-keys"], Store, "key sequence for detaching from the container");
+        ap.refer(&mut res_conf.memory_limit).add_option(&["-m"], Store, "memory limit");
+        ap.refer(&mut res_conf.memory_swap).add_option(&["--memory-swap"], Store, "swap limit equal to memory plus swap, -1 for unlimited");
+        ap.refer(&mut res_conf.memory_reservation).add_option(&["--memory-reservation"], Store, "memory soft limit");
+        ap.refer(&mut res_conf.kernel_memory).add_option(&["--kernel-memory"], Store, "kernel memory limit");
+        ap.refer(&mut oom_kill_disable).add_option(&["--oom-kill-disable"], StoreTrue, "disable OOM killer");
+        ap.refer(&mut res_conf.memory_swappiness).add_option(&["--memory-swappiness"], Store, "tune container memory swappiness (0 to 100)");
+        ap.refer(&mut res_conf.cpu_share).add_option(&["--cpushare"], Store, "cpushare limit");
+        ap.refer(&mut res_conf.cpus).add_option(&["--cpus"], Store, "number of CPUs");
+        ap.refer(&mut res_conf.cpu_set).add_option(&["--cpuset"], Store, "cpuset limit");
+        ap.refer(&mut res_conf.pids_limit).add_option(&["--pids-limit"], Store, "tune container pids limit (-1 for unlimited)");
+        ap.refer(&mut res_conf.blkio_weight).add_option(&["--blkio-weight"], Store, "block IO weight (10-1000)");
+        ap.refer(&mut res_conf.device_read_bps).add_option(&["--device-read-bps"], Collect, "limit read rate (bytes per second) from a device");
+        ap.refer(&mut res_conf.device_write_bps).add_option(&["--device-write-bps"], Collect, "limit write rate (bytes per second) to a device");
+        ap.refer(&mut res_conf.device_read_iops).add_option(&["--device-read-iops"], Collect, "limit read rate (IO per second) from a device");
+        ap.refer(&mut res_conf.device_write_iops).add_option(&["--device-write-iops"], Collect, "limit write rate (IO per second) to a device");
+        ap.refer(&mut container_name).add_option(&["--name"], Store, "container name");
+        ap.refer(&mut cgroup_parent).add_option(&["--cgroup-parent"], Store, "optional parent cgroup for the container");
+        ap.refer(&mut volume).add_option(&["-v"], Store, "volume");
+        ap.refer(&mut shm_size).add_option(&["--shm-size"], Store, "size of /dev/shm");
+        ap.refer(&mut cap_add).add_option(&["--cap-add"], Collect, "add Linux capabilities");
+        ap.refer(&mut cap_drop).add_option(&["--cap-drop"], Collect, "drop Linux capabilities");
+        ap.refer(&mut privileged).add_option(&["--privileged"], StoreTrue, "give extended privileges to this container");
+        ap.refer(&mut auto_remove).add_option(&["--rm"], StoreTrue, "automatically remove the container when it exits");
+        ap.refer(&mut restart).add_option(&["--restart"], Store, "restart policy: no, on-failure[:max-retries], always or unless-stopped");
+        ap.refer(&mut health_cmd).add_option(&["--health-cmd"], Store, "command to run to check health");
+        ap.refer(&mut health_interval).add_option(&["--health-interval"], Store, "time between running the check (ms|s|m|h) (default 30s)");
+        ap.refer(&mut health_timeout).add_option(&["--health-timeout"], Store, "maximum time to allow one check to run (ms|s|m|h) (default 30s)");
+        ap.refer(&mut health_retries).add_option(&["--health-retries"], Store, "consecutive failures needed to report unhealthy");
+        ap.refer(&mut health_start_period).add_option(&["--health-start-period"], Store, "start period for the container to initialize before failures count (ms|s|m|h) (default 0s)");
+        ap.refer(&mut restart_unhealthy).add_option(&["--restart-unhealthy"], StoreTrue, "restart the container when it becomes unhealthy");
+        ap.refer(&mut init).add_option(&["--init"], StoreTrue, "run an init inside the container that forwards signals and reaps processes");
+        ap.refer(&mut user).add_option(&["-u", "--user"], Store, "username or UID (format: <name|uid>[:<group|gid>])");
+        ap.refer(&mut userns_remap).add_option(&["--userns-remap"], Store, "run in a user namespace mapped to <user>'s subordinate ids, \"default\" or \"host\"");
+        ap.refer(&mut security_opts).add_option(&["--security-opt"], Collect, "security options, masked-path=<path>, readonly-path=<path>, seccomp=<profile.json|unconfined> or no-new-privileges");
+        ap.refer(&mut res_conf.devices).add_option(&["--device"], Collect, "add a host device to the container, <host>[:<container>][:<rwm>]");
+        ap.refer(&mut env_vars).add_option(&["-e"], Collect, "set environment variables, KEY=VALUE");
+        ap.refer(&mut hostname).add_option(&["--hostname"], Store, "container host name, the container id by default");
+        ap.refer(&mut workdir).add_option(&["-w", "--workdir"], Store, "working directory inside the container");
+        ap.refer(&mut ulimits).add_option(&["--ulimit"], Collect, "ulimit options, <name>=<soft>[:<hard>]");
+        ap.refer(&mut network).add_option(&["--net"], Store, "container network");
+        ap.refer(&mut portmapping).add_option(&["-p"], Store, "port mapping");
+        ap.refer(&mut image_name).add_argument("image_name", Store, "image name");
+        ap.refer(&mut args).add_argument("command", List, "command and its arguments");
+        // everything after the image belongs to the container's command
+        ap.stop_on_first_argument(true);
+        if let Err(code) = ap.parse(input_args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            return code;
+        }
+    }
+
+    if args.is_empty() {
         error!("Missing container command");
-        return;
+        return 1;
     }
 
-    let mut ap = ArgumentParser::new();
-    ap.refer(&mut create_tty).add_option(&["-ti"], StoreFalse, "enable tty");
-    ap.refer(&mut detach).add_option(&["-d"], StoreFalse, "detach container");
-    ap.refer(&mut res_conf.memory_limit).add_option(&["-m"], Store, "memory limit");
-    ap.refer(&mut res_conf.cpu_share).add_option(&["--cpushare"], Store, "cpushare limit");
-    ap.refer(&mut res_conf.cpu_set).add_option(&["--cpuset"], Store, "cpuset limit");
-    ap.refer(&mut container_name).add_option(&["--name"], Store, "container name");
-    ap.refer(&mut volume).add_option(&["-v"], Store, "volume");
-    ap.refer(&mut env_slice).add_option(&["-e"], Store, "set environment");
-    ap.refer(&mut network).add_option(&["--net"], Store, "container network");
-    ap.refer(&mut portmapping).add_option(&["-p"], Store, "port mapping");
-    ap.refer(&mut image_name).add_argument("image_name", Store, "image name");
-    ap.refer(&mut cname).add_argument("command", Store, "command");
+    let detach_keys = match parse_detach_keys(&detach_keys) {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
 
-    if create_tty && detach {
-        error!("ti and d paramter can not both provided");
-        return;
+    let restart_policy = match RestartPolicy::parse(&restart) {
+        Ok(policy) => policy,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+    if auto_remove && restart_policy != RestartPolicy::No {
+        error!("Conflicting options: --restart and --rm");
+        return 1;
+    }
+
+    let healthcheck = if health_cmd == "" {
+        if restart_unhealthy {
+            error!("--restart-unhealthy needs a --health-cmd");
+            return 1;
+        }
+        None
+    } else {
+        let parse = |spec: &str, default: Duration| if spec == "" { Ok(default) } else { parse_duration(spec) };
+        match (parse(&health_interval, DEFAULT_INTERVAL), parse(&health_timeout, DEFAULT_TIMEOUT),
+               parse(&health_start_period, Duration::from_secs(0))) {
+            (Ok(interval), Ok(timeout), Ok(start_period)) => Some(HealthConfig {
+                cmd: health_cmd,
+                interval: interval,
+                timeout: timeout,
+                retries: health_retries,
+                start_period: start_period,
+                restart_unhealthy: restart_unhealthy,
+            }),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                error!("{}", e);
+                return 1;
+            }
+        }
+    };
+
+    if oom_kill_disable {
+        res_conf.oom_kill_disable = Some(true);
+    }
+    if let Err(e) = res_conf.validate() {
+        error!("Invalid resource config: {}", e);
+        return 1;
     }
 
 	let container_id := rand_string_bytes(10);
     if container_name = "" {
         container_name = container_id;
     }
-    let (&cmd, piped_writer) = match new_parent_process(create_tty, &container_name, &volume, &image_name, &env_slice) {
-        Ok((&cmd, piped_writer)) => (&cmd, piped_writer),
+    let mut devices = Vec::new();
+    for spec in &res_conf.devices {
+        match Device::parse(spec) {
+            Ok(device) => devices.push(device),
+            Err(e) => {
+                error!("Invalid device: {}", e);
+                return 1;
+            }
+        }
+    }
+
+    let mut rlimits = Vec::new();
+    for spec in &ulimits {
+        match Rlimit::parse(spec) {
+            Ok(rlimit) => rlimits.push(rlimit),
+            Err(e) => {
+                error!("Invalid ulimit: {}", e);
+                return 1;
+            }
+        }
+    }
+
+    let shm_size = if shm_size == "" {
+        DEFAULT_SHM_SIZE
+    } else {
+        match parse_size(&shm_size) {
+            Ok(size) if size > 0 => size,
+            _ => {
+                error!("Invalid --shm-size {}", shm_size);
+                return 1;
+            }
+        }
+    };
+
+    let mut mounts = default_mounts(shm_size);
+    if volume != "" {
+        match MountSpec::volume(&volume) {
+            Ok(m) => {
+                // like docker, a missing host directory is created
+                if let Err(e) = create_dir_all(&m.source) {
+                    error!("Can't create volume {} : {}", m.source, e);
+                    return 1;
+                }
+                mounts.push(m);
+            }
+            Err(e) => {
+                error!("{}", e);
+                return 1;
+            }
+        }
+    }
+
+    let mut security = match SecurityOptions::parse(&security_opts) {
+        Ok(security) => security,
         Err(e) => {
-            error!("New parent process error: {}", e);
-            return
+            error!("{}", e);
+            return 1;
         }
+    };
+    security.capabilities = match resolve_capabilities(&cap_add, &cap_drop, privileged) {
+        Ok(caps) => caps,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+    if privileged {
+        security.masked_paths.clear();
+        security.readonly_paths.clear();
+        security.seccomp = None;
     }
 
-    // recordContainerInfo(&cmd.pid, &input_args, &container_id, &volume);
-    let mut child = cmd.spawn().map_err(|e| error!("New parent process error: {}", e)).unwrap();
-    let child_pid = child.pid;
+    if userns_remap == "" {
+        userns_remap = match DaemonConfig::load() {
+            Ok(config) => config.userns_remap,
+            Err(e) => {
+                error!("Load daemon config error {}", e);
+                return 1;
+            }
+        };
+    }
+    let id_mappings = match &userns_remap[..] {
+        // an unprivileged user can only ever map itself to root
+        _ if is_rootless() => {
+            if userns_remap == "host" {
+                error!("Rootless containers always run in a user namespace");
+                return 1;
+            }
+            match IdMappings::rootless() {
+                Ok(mappings) => Some(mappings),
+                Err(e) => {
+                    error!("Rootless user namespace error {}", e);
+                    return 1;
+                }
+            }
+        }
+        "" | "host" => None,
+        user => match IdMappings::for_user(user) {
+            Ok(mappings) => Some(mappings),
+            Err(e) => {
+                error!("Userns remap error {}", e);
+                return 1;
+            }
+        },
+    };
+
+    // the path is joined onto each hierarchy root, so it must stay relative
+    let cgroup_path = Path::new(cgroup_parent.trim_start_matches('/')).join(format!("mydocker-{}", container_id));
+    let cgroup_path = if is_rootless() {
+        match delegated_cgroup_root() {
+            Some(root) => Some(root.join(cgroup_path)),
+            None => {
+                warn!("No delegated cgroup v2 subtree, running without resource limits");
+                None
+            }
+        }
+    } else {
+        Some(cgroup_path)
+    };
+    let info = ContainerInfo {
+        id: container_id.clone(),
+        name: container_name.clone(),
+        command: args.join(" "),
+        create_time: now(),
+        status: RUNNING.to_string(),
+        volume: volume.clone(),
+        cgroup_path: cgroup_path.as_ref().map(|p| p.to_string_lossy().into_owned()).unwrap_or_default(),
+        resources: res_conf.clone(),
+        id_mappings: id_mappings.clone(),
+        auto_remove: auto_remove,
+        restart_policy: restart_policy,
+        healthcheck: healthcheck,
+        ..ContainerInfo::default()
+    };
+
+    let init_config = InitConfig {
+        args: args,
+        env: env_vars,
+        cwd: workdir,
+        user: user,
+        hostname: if hostname == "" { container_id.clone() } else { hostname },
+        mounts: mounts,
+        rlimits: rlimits,
+        devices: devices,
+        security: security,
+        init: init,
+        tty: create_tty,
+    };
+    let shim_config = ShimConfig {
+        info: info,
+        init: init_config,
+        tty: create_tty,
+        detach: detach,
+        image_name: image_name,
+        id_mappings: id_mappings,
+    };
+    let shim = match start_shim(shim_config) {
+        Ok(shim) => shim,
+        Err(e) => {
+            error!("Start container error: {}", e);
+            return 1;
+        }
+    };
 
-    let cgroup_manager = CgroupManager::new_cgroup_manager(input_args[0]);
-    cgroup_manager.set(res_conf);
-    cgroup_manager.apply(child_pid);
+    if detach {
+        println!("{}", container_id);
+        return 0;
+    }
+    match attach(&container_name, &detach_keys, interactive) {
+        // the container keeps running without us
+        Ok(true) => {
+            info!("Detached from container {}", container_name);
+            return 0;
+        }
+        Ok(false) => {}
+        Err(e) => error!("Attach error {}", e),
+    }
+    // a container about to be restarted has exited as far as `run` is concerned
+    match shim.wait() {
+        Ok(code) => code,
+        Err(e) => {
+            error!("Wait container {} error {}", container_name, e);
+            1
+        }
+    }
+}
+
+fn list_containers() -> i32 {
+    let containers = match list_container_info() {
+        Ok(containers) => containers,
+        Err(e) => {
+            error!("List container info error {}", e);
+            return 1;
+        }
+    };
+    println!("{:<12}{:<16}{:<10}{:<20}{:<12}{:<24}{}", "ID", "NAME", "PID", "STATUS", "OOMKILLED", "COMMAND", "CREATED");
+    for info in containers {
+        let status = match (info.exit_code, &info.health) {
+            (Some(code), _) if info.status == EXIT => format!("{} ({})", info.status, code),
+            (_, &Some(ref health)) if info.status == RUNNING => format!("{} ({})", info.status, health.status),
+            _ => info.status.clone(),
+        };
+        println!("{:<12}{:<16}{:<10}{:<20}{:<12}{:<24}{}",
+                 info.id, info.name, info.pid, status, info.oom_killed, info.command, info.create_time);
+    }
+    0
+}
 
-    send_init_command(input_args, piped_writer);
+fn inspect_container(input_args: Vec<String>) -> i32 {
+    if input_args.len() < 1 {
+        error!("Missing container name");
+        return 1;
+    }
+    match get_container_info(&input_args[0]) {
+        Ok(info) => {
+            println!("{}", serde_json::to_string_pretty(&info).unwrap());
+            0
+        }
+        Err(e) => {
+            error!("Inspect container {} error {}", input_args[0], e);
+            1
+        }
+    }
+}
 
-    if let Err(e) = piped_writer.write_all(input_args.as_bytes()) {
-        error!("pipe write error: {}", e);
-        return
+fn stats_command(input_args: Vec<String>) -> i32 {
+    let mut no_stream = false;
+    let mut format = String::new();
+    let mut names: Vec<String> = Vec::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.refer(&mut no_stream).add_option(&["--no-stream"], StoreTrue, "print the first result and exit");
+        ap.refer(&mut format).add_option(&["--format"], Store, "output format: table (default) or json");
+        ap.refer(&mut names).add_argument("container", Collect, "containers to show, all running if empty");
+        if let Err(code) = ap.parse(input_args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            return code;
+        }
     }
+    let json = match &format[..] {
+        "" | "table" => false,
+        "json" => true,
+        _ => {
+            error!("Unknown stats format {}", format);
+            return 1;
+        }
+    };
 
-    if create_tty {
-        match child.wait() {
-            Ok(status) => {
-                if status.success() {
-                    delete_work_space(volume, container_name);
+    let mut prev: HashMap<String, ContainerStats> = HashMap::new();
+    // the first sample only primes the CPU counters
+    let mut first = true;
+    loop {
+        let containers = match list_container_info() {
+            Ok(containers) => containers,
+            Err(e) => {
+                error!("List container info error {}", e);
+                return 1;
+            }
+        };
+        let mut samples = Vec::new();
+        for info in containers {
+            if info.status != RUNNING || (!names.is_empty() && !names.contains(&info.name) && !names.contains(&info.id)) {
+                continue;
+            }
+            if info.cgroup_path == "" {
+                continue;
+            }
+            let cgroup_stats = match CgroupManager::new_cgroup_manager(Path::new(&info.cgroup_path)).stats() {
+                Ok(stats) => stats,
+                Err(e) => {
+                    warn!("Can't read cgroup stats of {}: {}", info.name, e);
+                    continue;
                 }
+            };
+            let net_stats = read_network_stats(info.pid).unwrap_or_default();
+            let sample = ContainerStats::sample(&info.name, cgroup_stats, net_stats, prev.get(&info.name));
+            prev.insert(info.name.clone(), sample.clone());
+            samples.push(sample);
+        }
+
+        if first {
+            first = false;
+            sleep(Duration::from_millis(500));
+            continue;
+        }
+        if json {
+            for sample in &samples {
+                println!("{}", serde_json::to_string(sample).unwrap());
+            }
+        } else {
+            if !no_stream {
+                print!("\x1b[2J\x1b[H");
+            }
+            println!("{:<16}{:<10}{:<24}{:<10}{:<24}{:<24}{}",
+                     "NAME", "CPU %", "MEM USAGE / LIMIT", "MEM %", "NET I/O", "BLOCK I/O", "PIDS");
+            for s in &samples {
+                let limit = s.memory_limit.map(human_size).unwrap_or("unlimited".to_string());
+                println!("{:<16}{:<10}{:<24}{:<10}{:<24}{:<24}{}",
+                         s.name, format!("{:.2}%", s.cpu_percent),
+                         format!("{} / {}", human_size(s.memory_usage), limit),
+                         format!("{:.2}%", s.memory_percent),
+                         format!("{} / {}", human_size(s.net_rx), human_size(s.net_tx)),
+                         format!("{} / {}", human_size(s.block_read), human_size(s.block_write)),
+                         s.pids);
+            }
+        }
+        if no_stream {
+            return 0;
+        }
+        sleep(Duration::from_secs(1));
+    }
+}
+
+fn attach_container(input_args: Vec<String>) -> i32 {
+    let mut detach_keys = DEFAULT_DETACH_KEYS.to_string();
+    let mut no_stdin = false;
+    let mut name = String::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.refer(&mut detach_keys).add_option(&["--detach-keys"], Store, "key sequence for detaching from the container");
+        ap.refer(&mut no_stdin).add_option(&["--no-stdin"], StoreTrue, "do not attach stdin");
+        ap.refer(&mut name).add_argument("container", Store, "container to attach to").required();
+        if let Err(code) = ap.parse(input_args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            return code;
+        }
+    }
+    let detach_keys = match parse_detach_keys(&detach_keys) {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+    let info = match get_container_info(&name) {
+        Ok(info) => info,
+        Err(e) => {
+            error!("Get container {} info error {}", name, e);
+            return 1;
+        }
+    };
+    if info.status != RUNNING && info.status != PAUSED {
+        error!("Container {} is not running", info.name);
+        return 1;
+    }
+    match attach(&info.name, &detach_keys, !no_stdin) {
+        Ok(true) => info!("Detached from container {}", info.name),
+        Ok(false) => {}
+        Err(e) => {
+            error!("Attach container {} error {}", info.name, e);
+            return 1;
+        }
+    }
+    0
+}
+
+/// Internal, runs health checks inside a container for its monitor.
+fn nsenter_command(input_args: Vec<String>) -> i32 {
+    let mut target: libc::pid_t = 0;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.refer(&mut target).add_option(&["--target"], Store, "pid whose namespaces to enter").required();
+        if let Err(code) = ap.parse(input_args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            return code;
+        }
+    }
+    // the command, its user and its confinement come like init's, as an InitConfig
+    let config = match read_init_config(&mut std::io::stdin()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            return 126;
+        }
+    };
+    match exec_in_container(target, &config) {
+        Ok(code) => code,
+        Err(e) => {
+            error!("Enter container of {} error {}", target, e);
+            126
+        }
+    }
+}
+
+fn stop_command(input_args: Vec<String>) -> i32 {
+    let mut timeout: u64 = 10;
+    let mut names: Vec<String> = Vec::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.refer(&mut timeout).add_option(&["-t", "--time"], Store, "seconds to wait for stop before killing it");
+        ap.refer(&mut names).add_argument("container", Collect, "containers to stop").required();
+        if let Err(code) = ap.parse(input_args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            return code;
+        }
+    }
+    let mut code = 0;
+    for name in &names {
+        match stop_container(name, Duration::from_secs(timeout)) {
+            Ok(()) => println!("{}", name),
+            Err(e) => {
+                error!("Stop container {} error {}", name, e);
+                code = 1;
             }
         }
     }
+    code
+}
+
+/// SIGTERM, then SIGKILL after `timeout`. The container is marked first, so
+/// its monitor doesn't restart it whatever the restart policy.
+fn stop_container(name: &str, timeout: Duration) -> Result<(), String> {
+    let info = get_container_info(name)?;
+    if info.status == EXIT {
+        return Ok(());
+    }
+    update_container_info(name, |info| info.manually_stopped = true)?;
+    // the monitor notices on its own once the backoff is over
+    if info.status == RESTARTING {
+        return Ok(());
+    }
+    // a frozen process can't act on SIGTERM
+    if info.status == PAUSED && info.cgroup_path != "" {
+        CgroupManager::new_cgroup_manager(Path::new(&info.cgroup_path)).thaw()?;
+    }
+    send_signal(info.pid, libc::SIGTERM)?;
+    let deadline = SystemTime::now() + timeout;
+    while SystemTime::now() < deadline {
+        if container_exited(name, info.monitor_pid) {
+            return Ok(());
+        }
+        sleep(Duration::from_millis(100));
+    }
+    warn!("Container {} didn't stop in {:?}, killing it", name, timeout);
+    send_signal(info.pid, libc::SIGKILL)?;
+    while !container_exited(name, info.monitor_pid) {
+        sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+/// Exited as recorded by the monitor, or without a monitor left to record it.
+/// An auto removed container has no state left, its monitor exits right after.
+fn container_exited(name: &str, monitor_pid: libc::pid_t) -> bool {
+    let monitor_gone = unsafe { libc::kill(monitor_pid, 0) } != 0;
+    match get_container_info(name) {
+        Ok(info) => info.status == EXIT || monitor_gone,
+        Err(_) => monitor_gone,
+    }
+}
+
+fn send_signal(pid: libc::pid_t, sig: libc::c_int) -> Result<(), String> {
+    if unsafe { libc::kill(pid, sig) } != 0 {
+        let err = std::io::Error::last_os_error();
+        // already gone
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            return Err(format!("Send signal {} to {} error {}", sig, pid, err));
+        }
+    }
+    Ok(())
+}
+
+fn pause_container(input_args: Vec<String>) -> i32 {
+    if input_args.len() < 1 {
+        error!("Missing container name");
+        return 1;
+    }
+    let info = match get_container_info(&input_args[0]) {
+        Ok(info) => info,
+        Err(e) => {
+            error!("Get container {} info error {}", input_args[0], e);
+            return 1;
+        }
+    };
+    if info.status != RUNNING {
+        error!("Container {} is not running", info.name);
+        return 1;
+    }
+    if info.cgroup_path == "" {
+        error!("Container {} runs without cgroups", info.name);
+        return 1;
+    }
+    if let Err(e) = CgroupManager::new_cgroup_manager(Path::new(&info.cgroup_path)).freeze() {
+        error!("Pause container {} error {}", info.name, e);
+        return 1;
+    }
+    if let Err(e) = update_container_info(&info.name, |info| info.status = PAUSED.to_string()) {
+        error!("Update container info error {}", e);
+        return 1;
+    }
+    println!("{}", info.name);
+    0
+}
+
+fn unpause_container(input_args: Vec<String>) -> i32 {
+    if input_args.len() < 1 {
+        error!("Missing container name");
+        return 1;
+    }
+    let info = match get_container_info(&input_args[0]) {
+        Ok(info) => info,
+        Err(e) => {
+            error!("Get container {} info error {}", input_args[0], e);
+            return 1;
+        }
+    };
+    if info.status != PAUSED {
+        error!("Container {} is not paused", info.name);
+        return 1;
+    }
+    if info.cgroup_path == "" {
+        error!("Container {} runs without cgroups", info.name);
+        return 1;
+    }
+    if let Err(e) = CgroupManager::new_cgroup_manager(Path::new(&info.cgroup_path)).thaw() {
+        error!("Unpause container {} error {}", info.name, e);
+        return 1;
+    }
+    if let Err(e) = update_container_info(&info.name, |info| info.status = RUNNING.to_string()) {
+        error!("Update container info error {}", e);
+        return 1;
+    }
+    println!("{}", info.name);
+    0
+}
+
+fn update_container(input_args: Vec<String>) -> i32 {
+    let mut res_conf = ResourceConfig::default();
+    let mut container_name = String::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.refer(&mut res_conf.memory_limit).add_option(&["-m", "--memory"], Store, "memory limit");
+        ap.refer(&mut res_conf.memory_swap).add_option(&["--memory-swap"], Store, "swap limit equal to memory plus swap, -1 for unlimited");
+        ap.refer(&mut res_conf.memory_reservation).add_option(&["--memory-reservation"], Store, "memory soft limit");
+        ap.refer(&mut res_conf.oom_kill_disable).add_option(&["--oom-kill-disable"], StoreOption, "true or false, whether the OOM killer is disabled");
+        ap.refer(&mut res_conf.cpu_share).add_option(&["--cpushare"], Store, "cpushare limit");
+        ap.refer(&mut res_conf.cpus).add_option(&["--cpus"], Store, "number of CPUs");
+        ap.refer(&mut res_conf.cpu_set).add_option(&["--cpuset"], Store, "cpuset limit");
+        ap.refer(&mut res_conf.pids_limit).add_option(&["--pids-limit"], Store, "tune container pids limit (-1 for unlimited)");
+        ap.refer(&mut res_conf.blkio_weight).add_option(&["--blkio-weight"], Store, "block IO weight (10-1000)");
+        ap.refer(&mut container_name).add_argument("container", Store, "container name").required();
+        if let Err(code) = ap.parse(input_args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            return code;
+        }
+    }
+
+    let info = match get_container_info(&container_name) {
+        Ok(info) => info,
+        Err(e) => {
+            error!("Get container {} info error {}", container_name, e);
+            return 1;
+        }
+    };
+    if info.status != RUNNING && info.status != PAUSED {
+        error!("Container {} is not running", container_name);
+        return 1;
+    }
+    let mut merged = info.resources.clone();
+    merged.merge(&res_conf);
+    if let Err(e) = merged.validate() {
+        error!("Invalid resource config: {}", e);
+        return 1;
+    }
+
+    if info.cgroup_path == "" {
+        error!("Container {} runs without cgroups", info.name);
+        return 1;
+    }
+    let cgroup_manager = CgroupManager::new_cgroup_manager(Path::new(&info.cgroup_path));
+    let mut failed = false;
+    // limits are computed from each other, e.g. v2 swap from memory, so the
+    // whole config goes down and not only the options given here
+    for (subsystem, result) in cgroup_manager.update(&merged) {
+        match result {
+            Ok(()) => println!("{}: ok", subsystem),
+            Err(e) => {
+                println!("{}: {}", subsystem, e);
+                failed = true;
+            }
+        }
+    }
+    // only persist limits once the kernel accepted all of them
+    if failed {
+        error!("Update container {} partially failed, limits not saved", container_name);
+        return 1;
+    }
+    if let Err(e) = update_container_info(&container_name, |info| info.resources = merged) {
+        error!("Update container info error {}", e);
+        return 1;
+    }
+    0
+}
+
+fn now() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    secs.to_string()
 }
 
 fn rand_string_bytes(n :u32) -> String {
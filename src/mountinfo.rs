@@ -0,0 +1,149 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// One line of `/proc/<pid>/mountinfo`, see proc(5).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountInfo {
+    pub mount_id: u32,
+    pub parent_id: u32,
+    pub major: u32,
+    pub minor: u32,
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    pub mount_options: Vec<String>,
+    pub optional_fields: Vec<String>,
+    pub fstype: String,
+    pub source: String,
+    pub super_options: Vec<String>
+}
+
+impl MountInfo {
+    pub fn has_super_option(&self, opt: &str) -> bool {
+        self.super_options.iter().any(|o| o == opt)
+    }
+}
+
+lazy_static! {
+    static ref SELF_MOUNTINFO: Mutex<Option<Arc<Vec<MountInfo>>>> = Mutex::new(None);
+}
+
+/// The mount table of the current process, parsed once and then reused.
+pub fn self_mounts() -> Result<Arc<Vec<MountInfo>>, String> {
+    let mut cache = SELF_MOUNTINFO.lock().unwrap();
+    if let Some(ref mounts) = *cache {
+        return Ok(mounts.clone());
+    }
+    let mounts = Arc::new(parse_mountinfo(Path::new("/proc/self/mountinfo"))?);
+    *cache = Some(mounts.clone());
+    Ok(mounts)
+}
+
+/// Drop the cached mount table, to be called after mounting or unmounting.
+pub fn invalidate() {
+    *SELF_MOUNTINFO.lock().unwrap() = None;
+}
+
+pub fn is_mounted<P: AsRef<Path>>(mount_point: P) -> Result<bool, String> {
+    let mount_point = mount_point.as_ref();
+    Ok(self_mounts()?.iter().any(|m| m.mount_point == mount_point))
+}
+
+pub fn parse_mountinfo(path: &Path) -> Result<Vec<MountInfo>, String> {
+    let file = File::open(path).map_err(|e| format!("Can't open {:?} : {}", path, e))?;
+    let mut mounts = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("Error reading {:?} : {}", path, e))?;
+        mounts.push(parse_line(&line)?);
+    }
+    Ok(mounts)
+}
+
+pub fn parse_line(line: &str) -> Result<MountInfo, String> {
+    let err = || format!("Malformed mountinfo line {:?}", line);
+    let fields: Vec<&str> = line.split(' ').collect();
+    // the optional fields start at index 6 and are terminated by a lone "-"
+    if fields.len() < 10 {
+        return Err(err());
+    }
+    let sep = fields[6..].iter().position(|f| *f == "-").map(|p| p + 6).ok_or_else(err)?;
+    if fields.len() < sep + 4 {
+        return Err(err());
+    }
+    let mut dev = fields[2].splitn(2, ':');
+    let major = dev.next().and_then(|v| v.parse().ok()).ok_or_else(err)?;
+    let minor = dev.next().and_then(|v| v.parse().ok()).ok_or_else(err)?;
+    Ok(MountInfo {
+        mount_id: fields[0].parse().map_err(|_| err())?,
+        parent_id: fields[1].parse().map_err(|_| err())?,
+        major: major,
+        minor: minor,
+        root: PathBuf::from(unescape(fields[3])),
+        mount_point: PathBuf::from(unescape(fields[4])),
+        mount_options: fields[5].split(',').map(|s| s.to_string()).collect(),
+        optional_fields: fields[6..sep].iter().map(|s| s.to_string()).collect(),
+        fstype: unescape(fields[sep + 1]),
+        source: unescape(fields[sep + 2]),
+        super_options: fields[sep + 3].split(',').map(|s| s.to_string()).collect()
+    })
+}
+
+/// The kernel escapes space, tab, newline and backslash as `\ooo` octal.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(|b| *b >= b'0' && *b <= b'7') {
+            out.push((bytes[i + 1] - b'0') * 64 + (bytes[i + 2] - b'0') * 8 + (bytes[i + 3] - b'0'));
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_without_optional_fields() {
+        let m = parse_line("22 1 8:1 / / rw,relatime - ext4 /dev/sda1 rw,errors=remount-ro").unwrap();
+        assert_eq!(m.mount_id, 22);
+        assert_eq!(m.parent_id, 1);
+        assert_eq!((m.major, m.minor), (8, 1));
+        assert_eq!(m.mount_point, PathBuf::from("/"));
+        assert_eq!(m.mount_options, vec!["rw", "relatime"]);
+        assert!(m.optional_fields.is_empty());
+        assert_eq!(m.fstype, "ext4");
+        assert_eq!(m.source, "/dev/sda1");
+        assert!(m.has_super_option("errors=remount-ro"));
+    }
+
+    #[test]
+    fn parse_optional_fields() {
+        let m = parse_line("35 24 0:30 / /sys/fs/cgroup/memory rw,nosuid shared:13 master:2 - cgroup cgroup rw,memory").unwrap();
+        assert_eq!(m.optional_fields, vec!["shared:13", "master:2"]);
+        assert_eq!(m.fstype, "cgroup");
+        assert!(m.has_super_option("memory"));
+    }
+
+    #[test]
+    fn separator_is_required() {
+        assert!(parse_line("35 24 0:30 / /mnt rw shared:13 cgroup cgroup rw,memory").is_err());
+        assert!(parse_line("35 24 0:30 / /mnt rw shared:13 - cgroup cgroup").is_err());
+    }
+
+    #[test]
+    fn unescape_octal() {
+        let m = parse_line(r"40 22 0:40 /a\134b /mnt/my\040dir rw - tmpfs my\040tmp rw").unwrap();
+        assert_eq!(m.root, PathBuf::from(r"/a\b"));
+        assert_eq!(m.mount_point, PathBuf::from("/mnt/my dir"));
+        assert_eq!(m.source, "my tmp");
+        assert_eq!(unescape(r"tab\011end\"), "tab\tend\\");
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs::{File, OpenOptions, metadata, read_to_string};
+use std::io::Error as IoError;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use libc::{c_int, pid_t, fork, waitpid, setns, getpid, prctl, dup2, PR_SET_PDEATHSIG, SIGKILL, STDIN_FILENO};
+use libc::{WIFEXITED, WEXITSTATUS, WIFSIGNALED, WTERMSIG};
+use container::prepare_exec;
+use init_config::InitConfig;
+use subsystem::{find_cgroup_mountpoint, is_cgroup_v2, write_cgroup_file};
+
+/// Joined in this order: the user namespace grants the rights for the
+/// others, and the mount namespace goes last since it changes what
+/// /proc and /sys paths resolve to.
+const NAMESPACES: [&str; 6] = ["user", "ipc", "uts", "net", "pid", "mnt"];
+
+/// Run `config.args` inside the namespaces and cgroups of the container
+/// whose init is `pid`, confined by the same settings as the container's
+/// command, and return its exit code. Must be called from a single threaded
+/// process, `mydocker nsenter` is one.
+pub fn exec_in_container(pid: pid_t, config: &InitConfig) -> Result<i32, String> {
+    // stdin carried the config, the command gets nothing to read
+    let null = OpenOptions::new().read(true).open("/dev/null").map_err(|e| format!("Can't open /dev/null : {}", e))?;
+    if unsafe { dup2(null.as_raw_fd(), STDIN_FILENO) } < 0 {
+        return Err(format!("Can't redirect stdin : {}", IoError::last_os_error()));
+    }
+    // every fd is opened up front, the paths stop resolving once we moved
+    let mut namespaces = Vec::new();
+    for ns in &NAMESPACES {
+        let path = format!("/proc/{}/ns/{}", pid, ns);
+        // joining the namespace we are already in fails for user namespaces
+        let same = match (metadata(&path), metadata(format!("/proc/self/ns/{}", ns))) {
+            (Ok(theirs), Ok(ours)) => theirs.ino() == ours.ino() && theirs.dev() == ours.dev(),
+            (Err(e), _) => return Err(format!("Can't stat {} : {}", path, e)),
+            (_, Err(_)) => false,
+        };
+        if !same {
+            namespaces.push((path.clone(), File::open(&path).map_err(|e| format!("Can't open {} : {}", path, e))?));
+        }
+    }
+    join_cgroups(pid)?;
+    for &(ref path, ref f) in &namespaces {
+        if unsafe { setns(f.as_raw_fd(), 0) } != 0 {
+            return Err(format!("Can't join {} : {}", path, IoError::last_os_error()));
+        }
+    }
+
+    // the pid namespace only applies to children
+    match unsafe { fork() } {
+        -1 => Err(format!("Can't fork : {}", IoError::last_os_error())),
+        0 => {
+            let err = exec_child(config);
+            error!("{}", err);
+            ::std::process::exit(127);
+        }
+        child => {
+            let mut status: c_int = 0;
+            if unsafe { waitpid(child, &mut status, 0) } < 0 {
+                return Err(format!("Wait {} error {}", child, IoError::last_os_error()));
+            }
+            Ok(if WIFEXITED(status) {
+                WEXITSTATUS(status)
+            } else if WIFSIGNALED(status) {
+                128 + WTERMSIG(status)
+            } else {
+                1
+            })
+        }
+    }
+}
+
+fn exec_child(config: &InitConfig) -> String {
+    // killing us on a timeout must not leave the command behind
+    if let Err(e) = set_parent_death_signal() {
+        return e;
+    }
+    // the container's passwd and group files and its working directory are visible now
+    let mut cmd = match prepare_exec(config) {
+        Ok(cmd) => cmd,
+        Err(e) => return e.to_string(),
+    };
+    // switching to another user cleared it
+    if let Err(e) = set_parent_death_signal() {
+        return e;
+    }
+    let err = cmd.exec();
+    format!("failed in exec {:?}: {}", config.args, err)
+}
+
+fn set_parent_death_signal() -> Result<(), String> {
+    if unsafe { prctl(PR_SET_PDEATHSIG, SIGKILL) } != 0 {
+        return Err(format!("Can't set parent death signal : {}", IoError::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Move ourselves into every cgroup `pid` is in, read from /proc/<pid>/cgroup.
+fn join_cgroups(pid: pid_t) -> Result<(), String> {
+    let path = format!("/proc/{}/cgroup", pid);
+    let content = read_to_string(&path).map_err(|e| format!("Can't read {} : {}", path, e))?;
+    let me = unsafe { getpid() }.to_string();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.splitn(3, ':').collect();
+        if fields.len() != 3 {
+            continue;
+        }
+        let cgroup = fields[2].trim_start_matches('/');
+        let dir = if fields[0] == "0" && fields[1] == "" {
+            if !is_cgroup_v2() {
+                continue;
+            }
+            Path::new("/sys/fs/cgroup").join(cgroup)
+        } else {
+            // any one controller of a co-mounted hierarchy finds its mount point
+            let controller = fields[1].split(',').next().unwrap_or("").trim_start_matches("name=");
+            match find_cgroup_mountpoint(Path::new(controller)) {
+                Ok(root) => root.join(cgroup),
+                Err(e) => {
+                    warn!("Skip cgroup {} : {}", line, e);
+                    continue;
+                }
+            }
+        };
+        write_cgroup_file(&dir, "cgroup.procs", &me)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, BufRead, BufReader};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use libc::{eventfd, EFD_CLOEXEC};
+use subsystem::{get_cgroup_path, is_cgroup_v2};
+
+/// Watches the memory cgroup of a container for OOM kills.
+///
+/// Once the container has exited the `oom_kill` counter in `memory.events`
+/// (v2) or `memory.oom_control` (v1) is consulted. Older v1 kernels lack the
+/// counter, there an eventfd registered on `memory.oom_control` records OOM
+/// events instead.
+pub struct OomWatcher {
+    memory_path: PathBuf,
+    triggered: Arc<AtomicBool>
+}
+
+impl OomWatcher {
+    pub fn new(cgroup_path: &Path) -> Result<OomWatcher, String> {
+        let memory_path = get_cgroup_path("memory", cgroup_path, false)?.to_path_buf();
+        let watcher = OomWatcher {
+            memory_path: memory_path,
+            triggered: Arc::new(AtomicBool::new(false))
+        };
+        if !is_cgroup_v2() {
+            watcher.register_eventfd()?;
+        }
+        Ok(watcher)
+    }
+
+    fn register_eventfd(&self) -> Result<(), String> {
+        let oom_control = File::open(self.memory_path.join("memory.oom_control"))
+            .map_err(|e| format!("Can't open memory.oom_control : {}", e))?;
+        let efd = unsafe { eventfd(0, EFD_CLOEXEC) };
+        if efd < 0 {
+            return Err(format!("eventfd error {}", ::std::io::Error::last_os_error()));
+        }
+        let mut event_file = unsafe { File::from_raw_fd(efd) };
+
+        let mut control = OpenOptions::new().write(true)
+            .open(self.memory_path.join("cgroup.event_control"))
+            .map_err(|e| format!("Can't open cgroup.event_control : {}", e))?;
+        control.write_all(format!("{} {}", efd, oom_control.as_raw_fd()).as_bytes())
+            .map_err(|e| format!("Can't register oom eventfd : {}", e))?;
+
+        let triggered = self.triggered.clone();
+        let memory_path = self.memory_path.clone();
+        thread::spawn(move || {
+            // keep oom_control open for as long as the registration lives
+            let _oom_control = oom_control;
+            let mut buf = [0u8; 8];
+            loop {
+                if event_file.read_exact(&mut buf).is_err() {
+                    return;
+                }
+                // the eventfd also fires when the cgroup is removed
+                if !memory_path.exists() {
+                    return;
+                }
+                triggered.store(true, Ordering::SeqCst);
+            }
+        });
+        Ok(())
+    }
+
+    /// Whether the kernel OOM killer has killed a process in the cgroup.
+    /// `sigkilled` tells whether the container's process died of SIGKILL, the
+    /// fallback evidence on kernels without an `oom_kill` counter.
+    pub fn oom_killed(&self, sigkilled: bool) -> bool {
+        let file = if is_cgroup_v2() { "memory.events" } else { "memory.oom_control" };
+        match read_oom_kill_count(&self.memory_path.join(file)) {
+            Ok(Some(count)) => count > 0,
+            // the v1 event also fires for OOMs that ended in reclaim or under
+            // oom_kill_disable, alone it doesn't prove a kill
+            Ok(None) => self.triggered.load(Ordering::SeqCst) && sigkilled,
+            Err(e) => {
+                warn!("Can't read OOM counter for {:?} : {}", self.memory_path, e);
+                self.triggered.load(Ordering::SeqCst) && sigkilled
+            }
+        }
+    }
+}
+
+/// The `oom_kill` counter, None on kernels older than 4.13 which lack it.
+fn read_oom_kill_count(path: &Path) -> Result<Option<u64>, String> {
+    let f = File::open(path).map_err(|e| format!("Can't open {:?} : {}", path, e))?;
+    for line in BufReader::new(f).lines() {
+        let line = line.map_err(|e| format!("Error reading {:?} : {}", path, e))?;
+        let mut fields = line.split_whitespace();
+        if fields.next() == Some("oom_kill") {
+            return fields.next().unwrap_or("0").parse().map(Some)
+                .map_err(|e| format!("Invalid oom_kill value in {:?} : {}", path, e));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,97 @@
+use std::io::Error as IoError;
+use std::mem::zeroed;
+use std::process::exit;
+use libc::{c_int, pid_t, sigset_t, siginfo_t, sigfillset, sigprocmask, sigwaitinfo, waitpid, kill, setpgid};
+use libc::{getpid, isatty, tcsetpgrp, SIG_SETMASK, SIGCHLD, WNOHANG, EACCES, EINTR, ESRCH, STDIN_FILENO};
+use libc::{WIFEXITED, WEXITSTATUS, WIFSIGNALED, WTERMSIG};
+
+/// Block every signal so they queue up for the reaper, returning the
+/// previous mask for the command to restore.
+pub fn block_signals() -> Result<sigset_t, String> {
+    unsafe {
+        let mut all: sigset_t = zeroed();
+        let mut old: sigset_t = zeroed();
+        sigfillset(&mut all);
+        if sigprocmask(SIG_SETMASK, &all, &mut old) != 0 {
+            return Err(format!("Can't block signals : {}", IoError::last_os_error()));
+        }
+        Ok(old)
+    }
+}
+
+/// Run in the forked command before exec: give it a process group of its
+/// own, the terminal if there is one, and the original signal mask.
+pub fn prepare_child(old_mask: &sigset_t) -> Result<(), String> {
+    unsafe {
+        if setpgid(0, 0) != 0 {
+            return Err(format!("Can't create process group : {}", IoError::last_os_error()));
+        }
+        // still blocked here, so taking the terminal can't stop us with SIGTTOU
+        if isatty(STDIN_FILENO) == 1 && tcsetpgrp(STDIN_FILENO, getpid()) != 0 {
+            return Err(format!("Can't take the terminal : {}", IoError::last_os_error()));
+        }
+        if sigprocmask(SIG_SETMASK, old_mask, ::std::ptr::null_mut()) != 0 {
+            return Err(format!("Can't restore signal mask : {}", IoError::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/// PID 1 of the container when `--init` is given: forward every signal to
+/// the command's process group, reap whatever gets reparented to us, and
+/// exit with the command's status once it is gone.
+pub fn reap(child: pid_t) -> ! {
+    // the child does the same, whichever runs first creates the group so no
+    // signal is forwarded into a group that doesn't exist yet. EACCES means
+    // the child already exec'd, and so already did it.
+    if unsafe { setpgid(child, child) } != 0 {
+        let err = IoError::last_os_error();
+        if err.raw_os_error() != Some(EACCES) {
+            warn!("Can't create process group of {} : {}", child, err);
+        }
+    }
+    let mut all: sigset_t = unsafe { zeroed() };
+    unsafe { sigfillset(&mut all) };
+    loop {
+        let mut info: siginfo_t = unsafe { zeroed() };
+        let sig = unsafe { sigwaitinfo(&all, &mut info) };
+        if sig < 0 {
+            let err = IoError::last_os_error();
+            if err.raw_os_error() == Some(EINTR) {
+                continue;
+            }
+            error!("Reaper wait for signals error {}", err);
+            exit(1);
+        }
+        if sig != SIGCHLD {
+            if unsafe { kill(-child, sig) } != 0 {
+                let err = IoError::last_os_error();
+                if err.raw_os_error() != Some(ESRCH) {
+                    warn!("Forward signal {} to {} error {}", sig, child, err);
+                }
+            }
+            continue;
+        }
+        // one SIGCHLD may stand for several exits
+        loop {
+            let mut status: c_int = 0;
+            let pid = unsafe { waitpid(-1, &mut status, WNOHANG) };
+            if pid <= 0 {
+                break;
+            }
+            if pid == child {
+                exit(exit_code(status));
+            }
+        }
+    }
+}
+
+fn exit_code(status: c_int) -> i32 {
+    if WIFEXITED(status) {
+        WEXITSTATUS(status)
+    } else if WIFSIGNALED(status) {
+        128 + WTERMSIG(status)
+    } else {
+        1
+    }
+}
@@ -0,0 +1,142 @@
+use std::cmp::min;
+use std::fmt;
+use std::time::Duration;
+
+/// Delay before the first restart, doubled after each quick failure.
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A container that ran at least this long starts over with the initial delay.
+pub const BACKOFF_RESET: Duration = Duration::from_secs(10);
+
+/// What the monitor does when the container exits. `always` and
+/// `unless-stopped` only differ across daemon restarts in docker, mydocker
+/// has no daemon, so both restart until an explicit `mydocker stop`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "name", rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    No,
+    /// Restart on a non-zero exit, at most `max_retries` times if not zero.
+    OnFailure { max_retries: u32 },
+    Always,
+    UnlessStopped,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> RestartPolicy {
+        RestartPolicy::No
+    }
+}
+
+impl RestartPolicy {
+    /// Parse `--restart no|on-failure[:N]|always|unless-stopped`.
+    pub fn parse(spec: &str) -> Result<RestartPolicy, String> {
+        let mut parts = spec.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let retries = parts.next();
+        match (name, retries) {
+            ("", None) | ("no", None) => Ok(RestartPolicy::No),
+            ("always", None) => Ok(RestartPolicy::Always),
+            ("unless-stopped", None) => Ok(RestartPolicy::UnlessStopped),
+            ("on-failure", None) => Ok(RestartPolicy::OnFailure { max_retries: 0 }),
+            ("on-failure", Some(n)) => n.parse()
+                .map(|n| RestartPolicy::OnFailure { max_retries: n })
+                .map_err(|_| format!("Invalid maximum retry count {} in restart policy {}", n, spec)),
+            (_, Some(_)) => Err(format!("Maximum retry count is only valid with on-failure: {}", spec)),
+            _ => Err(format!("Invalid restart policy {}", spec)),
+        }
+    }
+
+    /// Whether a container that exited with `code` after `restart_count`
+    /// restarts gets another one. A manual stop always wins.
+    pub fn should_restart(&self, code: i32, restart_count: u32, manually_stopped: bool) -> bool {
+        if manually_stopped {
+            return false;
+        }
+        match *self {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure { max_retries } => code != 0 && (max_retries == 0 || restart_count < max_retries),
+            RestartPolicy::Always | RestartPolicy::UnlessStopped => true,
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RestartPolicy::No => write!(f, "no"),
+            RestartPolicy::OnFailure { max_retries: 0 } => write!(f, "on-failure"),
+            RestartPolicy::OnFailure { max_retries } => write!(f, "on-failure:{}", max_retries),
+            RestartPolicy::Always => write!(f, "always"),
+            RestartPolicy::UnlessStopped => write!(f, "unless-stopped"),
+        }
+    }
+}
+
+/// Exponential backoff between restarts.
+pub struct Backoff {
+    delay: Duration
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff { delay: INITIAL_BACKOFF }
+    }
+
+    /// The delay before the next restart of a container that ran for `uptime`.
+    pub fn next(&mut self, uptime: Duration) -> Duration {
+        if uptime >= BACKOFF_RESET {
+            self.delay = INITIAL_BACKOFF;
+        }
+        let delay = self.delay;
+        self.delay = min(self.delay * 2, MAX_BACKOFF);
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_policies() {
+        assert_eq!(RestartPolicy::parse("").unwrap(), RestartPolicy::No);
+        assert_eq!(RestartPolicy::parse("no").unwrap(), RestartPolicy::No);
+        assert_eq!(RestartPolicy::parse("always").unwrap(), RestartPolicy::Always);
+        assert_eq!(RestartPolicy::parse("unless-stopped").unwrap(), RestartPolicy::UnlessStopped);
+        assert_eq!(RestartPolicy::parse("on-failure").unwrap(), RestartPolicy::OnFailure { max_retries: 0 });
+        assert_eq!(RestartPolicy::parse("on-failure:3").unwrap(), RestartPolicy::OnFailure { max_retries: 3 });
+        for spec in &["on-failure:x", "on-failure:-1", "always:3", "sometimes"] {
+            assert!(RestartPolicy::parse(spec).is_err(), "{} was accepted", spec);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for spec in &["no", "always", "unless-stopped", "on-failure", "on-failure:5"] {
+            assert_eq!(RestartPolicy::parse(spec).unwrap().to_string(), *spec);
+        }
+    }
+
+    #[test]
+    fn on_failure_counts_retries() {
+        let policy = RestartPolicy::OnFailure { max_retries: 2 };
+        assert!(!policy.should_restart(0, 0, false));
+        assert!(policy.should_restart(1, 1, false));
+        assert!(!policy.should_restart(1, 2, false));
+        assert!(RestartPolicy::OnFailure { max_retries: 0 }.should_restart(1, 100, false));
+        assert!(!RestartPolicy::Always.should_restart(0, 0, true));
+        assert!(!RestartPolicy::No.should_restart(1, 0, false));
+    }
+
+    #[test]
+    fn backoff_doubles_and_resets() {
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.next(Duration::from_secs(0)), INITIAL_BACKOFF);
+        assert_eq!(backoff.next(Duration::from_secs(0)), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff.next(BACKOFF_RESET), INITIAL_BACKOFF);
+        for _ in 0..20 {
+            backoff.next(Duration::from_secs(0));
+        }
+        assert_eq!(backoff.next(Duration::from_secs(0)), MAX_BACKOFF);
+    }
+}
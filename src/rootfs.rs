@@ -0,0 +1,287 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{create_dir_all, metadata, read_link, symlink_metadata, File};
+use std::io::Error as IoError;
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path, PathBuf};
+use std::ptr::null;
+use libc::{c_ulong, makedev, mknod, chmod, umask, S_IFCHR, ELOOP};
+use libc::{MS_NOEXEC, MS_NOSUID, MS_NODEV, MS_RDONLY, MS_BIND, MS_REC, MS_REMOUNT, MS_STRICTATIME};
+use init_config::MountSpec;
+
+pub const DEFAULT_SHM_SIZE: u64 = 64 << 20;
+
+/// Character devices every container gets: name, major, minor.
+const DEFAULT_DEVICES: [(&str, u32, u32); 6] = [
+    ("/dev/null", 1, 3),
+    ("/dev/zero", 1, 5),
+    ("/dev/full", 1, 7),
+    ("/dev/random", 1, 8),
+    ("/dev/urandom", 1, 9),
+    ("/dev/tty", 5, 0),
+];
+
+/// A failed step of the container's mount setup.
+#[derive(Debug)]
+pub enum MountError {
+    /// A mount related syscall on `target` failed.
+    Syscall { call: &'static str, target: String, err: IoError },
+    /// Creating, removing or resolving a path failed.
+    Io { op: &'static str, path: String, err: IoError },
+    /// A composite step, e.g. populating /dev, failed.
+    Setup { step: &'static str, message: String },
+}
+
+impl MountError {
+    pub fn syscall<P: AsRef<Path>>(call: &'static str, target: P) -> MountError {
+        MountError::Syscall {
+            call: call,
+            target: target.as_ref().display().to_string(),
+            err: IoError::last_os_error(),
+        }
+    }
+
+    pub fn io<P: AsRef<Path>>(op: &'static str, path: P, err: IoError) -> MountError {
+        MountError::Io { op: op, path: path.as_ref().display().to_string(), err: err }
+    }
+
+    pub fn errno(&self) -> i32 {
+        match *self {
+            MountError::Syscall { ref err, .. } | MountError::Io { ref err, .. } => err.raw_os_error().unwrap_or(0),
+            MountError::Setup { .. } => 0,
+        }
+    }
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MountError::Syscall { call, ref target, ref err } => write!(f, "{} {} : {}", call, target, err),
+            MountError::Io { op, ref path, ref err } => write!(f, "Can't {} {} : {}", op, path, err),
+            MountError::Setup { step, ref message } => write!(f, "Setup {} error {}", step, message),
+        }
+    }
+}
+
+impl From<MountError> for String {
+    fn from(e: MountError) -> String {
+        e.to_string()
+    }
+}
+
+/// Thin wrapper over mount(2) that reports the failing target.
+pub fn mount_fs(source: &str, target: &str, fstype: &str, flags: c_ulong, data: Option<&str>) -> Result<(), MountError> {
+    let c_source = CString::new(source).unwrap();
+    let c_target = CString::new(target).unwrap();
+    let c_fstype = CString::new(fstype).unwrap();
+    let c_data = data.map(|d| CString::new(d).unwrap());
+    let rc = unsafe {
+        ::libc::mount(c_source.as_ptr(), c_target.as_ptr(), c_fstype.as_ptr(), flags,
+                      c_data.as_ref().map(|d| d.as_ptr() as *const _).unwrap_or(null()))
+    };
+    if rc != 0 {
+        return Err(MountError::syscall("mount", target));
+    }
+    Ok(())
+}
+
+/// The filesystems every container gets, mounted in this order.
+pub fn default_mounts(shm_size: u64) -> Vec<MountSpec> {
+    let spec = |source: &str, destination: &str, fstype: &str, options: &[&str]| MountSpec {
+        source: source.to_string(),
+        destination: destination.to_string(),
+        fstype: fstype.to_string(),
+        options: options.iter().map(|o| o.to_string()).collect(),
+    };
+    vec![
+        spec("proc", "/proc", "proc", &["nosuid", "noexec", "nodev"]),
+        spec("tmpfs", "/dev", "tmpfs", &["nosuid", "strictatime", "mode=755"]),
+        spec("devpts", "/dev/pts", "devpts", &["nosuid", "noexec", "newinstance", "ptmxmode=0666", "mode=0620", "gid=5"]),
+        spec("shm", "/dev/shm", "tmpfs", &["nosuid", "noexec", "nodev", "mode=1777", &format!("size={}", shm_size)]),
+        spec("mqueue", "/dev/mqueue", "mqueue", &["nosuid", "noexec", "nodev"]),
+        // a container has no business reconfiguring the host's devices
+        spec("sysfs", "/sys", "sysfs", &["ro", "nosuid", "noexec", "nodev"]),
+    ]
+}
+
+/// Mount `m` at its destination below `root`, creating the mount point.
+/// Runs before the root switch, so the destination is resolved without
+/// letting the image's symlinks lead out of `root`.
+pub fn mount_spec(root: &Path, m: &MountSpec) -> Result<(), MountError> {
+    let mut flags = 0;
+    let mut data = Vec::new();
+    for opt in &m.options {
+        match &opt[..] {
+            "bind" => flags |= MS_BIND,
+            "rbind" => flags |= MS_BIND | MS_REC,
+            "ro" => flags |= MS_RDONLY,
+            "nosuid" => flags |= MS_NOSUID,
+            "nodev" => flags |= MS_NODEV,
+            "noexec" => flags |= MS_NOEXEC,
+            "strictatime" => flags |= MS_STRICTATIME,
+            other => data.push(other),
+        }
+    }
+    let target = resolve_in_root(root, &m.destination)?;
+    let bind = flags & MS_BIND != 0;
+    // a file can only be bound onto a file
+    if bind && !metadata(&m.source).map(|meta| meta.is_dir()).unwrap_or(true) {
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent).map_err(|e| MountError::io("create", parent, e))?;
+        }
+        if !target.exists() {
+            File::create(&target).map_err(|e| MountError::io("create", &target, e))?;
+        }
+    } else {
+        create_dir_all(&target).map_err(|e| MountError::io("create", &target, e))?;
+    }
+
+    let target = target.to_string_lossy();
+    let data = data.join(",");
+    let data = if data == "" { None } else { Some(&data[..]) };
+    if !bind {
+        return mount_fs(&m.source, &target, &m.fstype, flags, data);
+    }
+    mount_fs(&m.source, &target, &m.fstype, flags & (MS_BIND | MS_REC), data)?;
+    // a bind mount ignores every other flag until it is remounted
+    if flags & !(MS_BIND | MS_REC) != 0 {
+        mount_fs(&m.source, &target, &m.fstype, (flags & !MS_REC) | MS_REMOUNT, None)?;
+    }
+    Ok(())
+}
+
+/// Join `path` onto `root` the way the kernel would resolve it with `root` as
+/// `/`: symlinks are followed, but neither they nor `..` can climb out.
+pub fn resolve_in_root(root: &Path, path: &str) -> Result<PathBuf, MountError> {
+    let mut resolved = root.to_path_buf();
+    let mut pending: Vec<PathBuf> = Path::new(path).components().rev()
+        .map(|c| PathBuf::from(c.as_os_str())).collect();
+    let mut links = 0;
+    while let Some(next) = pending.pop() {
+        match next.components().next() {
+            Some(Component::Normal(name)) => resolved.push(name),
+            Some(Component::ParentDir) => {
+                if resolved != root {
+                    resolved.pop();
+                }
+                continue;
+            }
+            _ => continue,
+        }
+        let is_link = symlink_metadata(&resolved).map(|m| m.file_type().is_symlink()).unwrap_or(false);
+        if !is_link {
+            continue;
+        }
+        links += 1;
+        if links > 40 {
+            return Err(MountError::Io { op: "resolve", path: path.to_string(), err: IoError::from_raw_os_error(ELOOP) });
+        }
+        let target = read_link(&resolved).map_err(|e| MountError::io("read link", &resolved, e))?;
+        resolved.pop();
+        if target.is_absolute() {
+            resolved = root.to_path_buf();
+        }
+        pending.extend(target.components().rev().map(|c| PathBuf::from(c.as_os_str())));
+    }
+    Ok(resolved)
+}
+
+/// Fill the container's freshly mounted /dev tmpfs. Must run after the root switch.
+pub fn setup_dev() -> Result<(), String> {
+    // device nodes get exactly the mode we ask for
+    let old_mask = unsafe { umask(0) };
+    let result = create_default_devices();
+    unsafe { umask(old_mask) };
+    result?;
+
+    // every pty must come from the container's own devpts instance
+    symlink("pts/ptmx", "/dev/ptmx").map_err(|e| format!("Can't link /dev/ptmx : {}", e))?;
+    let links = [
+        ("/proc/self/fd", "/dev/fd"),
+        ("/proc/self/fd/0", "/dev/stdin"),
+        ("/proc/self/fd/1", "/dev/stdout"),
+        ("/proc/self/fd/2", "/dev/stderr"),
+    ];
+    for &(src, dst) in links.iter() {
+        symlink(src, dst).map_err(|e| format!("Can't link {} to {} : {}", dst, src, e))?;
+    }
+    Ok(())
+}
+
+/// Bind the host's default devices into `<root>/dev`. A user namespace may
+/// not mknod, but it may bind nodes the host already has.
+pub fn bind_host_devices(root: &Path) -> Result<(), String> {
+    for &(path, _, _) in DEFAULT_DEVICES.iter() {
+        let target = root.join(&path[1..]);
+        File::create(&target).map_err(|e| format!("Can't create {:?} : {}", target, e))?;
+        mount_fs(path, &target.to_string_lossy(), "bind", MS_BIND, None)?;
+    }
+    Ok(())
+}
+
+fn create_default_devices() -> Result<(), String> {
+    for &(path, major, minor) in DEFAULT_DEVICES.iter() {
+        if Path::new(path).exists() {
+            continue;
+        }
+        let c_path = CString::new(path).unwrap();
+        if unsafe { mknod(c_path.as_ptr(), S_IFCHR | 0o666, makedev(major, minor)) } != 0 {
+            return Err(format!("Can't mknod {} : {}", path, IoError::last_os_error()));
+        }
+        unsafe { chmod(c_path.as_ptr(), 0o666) };
+    }
+    Ok(())
+}
+
+/// Hide each path by mounting over it: a read-only tmpfs for directories,
+/// /dev/null for files. Paths the kernel doesn't provide are skipped.
+pub fn mask_paths(paths: &[String]) -> Result<(), String> {
+    for path in paths {
+        let meta = match metadata(path) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        if meta.is_dir() {
+            mount_fs("tmpfs", path, "tmpfs", MS_RDONLY, None)?;
+        } else {
+            mount_fs("/dev/null", path, "bind", MS_BIND, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Make each path read-only with a bind mount onto itself followed by a read-only remount.
+pub fn readonly_paths(paths: &[String]) -> Result<(), String> {
+    for path in paths {
+        if !Path::new(path).exists() {
+            continue;
+        }
+        mount_fs(path, path, "bind", MS_BIND | MS_REC, None)?;
+        mount_fs(path, path, "bind", MS_BIND | MS_REC | MS_REMOUNT | MS_RDONLY | MS_NOSUID | MS_NODEV | MS_NOEXEC, None)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+    use std::process;
+
+    #[test]
+    fn resolve_stays_in_root() {
+        let root = temp_dir().join(format!("mydocker-resolve-{}", process::id()));
+        create_dir_all(root.join("etc")).unwrap();
+        symlink("/etc", root.join("abs")).unwrap();
+        symlink("../../etc", root.join("rel")).unwrap();
+        symlink("loop", root.join("loop")).unwrap();
+
+        assert_eq!(resolve_in_root(&root, "/data").unwrap(), root.join("data"));
+        assert_eq!(resolve_in_root(&root, "/abs/x").unwrap(), root.join("etc/x"));
+        assert_eq!(resolve_in_root(&root, "/rel").unwrap(), root.join("etc"));
+        assert_eq!(resolve_in_root(&root, "/../../x").unwrap(), root.join("x"));
+        assert!(resolve_in_root(&root, "/loop").is_err());
+        remove_dir_all(&root).unwrap();
+    }
+}
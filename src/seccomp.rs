@@ -0,0 +1,465 @@
+use std::fs::File;
+use std::io::Error as IoError;
+use std::path::Path;
+use libc::{c_int, c_ulong, prctl, EPERM};
+use syscalls::syscall_number;
+use security::set_no_new_privs;
+
+/// A seccomp profile in the docker/OCI JSON format.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub default_action: String,
+    #[serde(default)]
+    pub default_errno_ret: Option<u32>,
+    #[serde(default)]
+    pub architectures: Vec<String>,
+    #[serde(default)]
+    pub arch_map: Vec<ArchMap>,
+    #[serde(default)]
+    pub syscalls: Vec<SyscallRule>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchMap {
+    pub architecture: String,
+    #[serde(default)]
+    pub sub_architectures: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallRule {
+    #[serde(default)]
+    pub names: Vec<String>,
+    // pre-1.13 docker profiles name a single syscall per rule
+    #[serde(default)]
+    pub name: Option<String>,
+    pub action: String,
+    #[serde(default)]
+    pub args: Vec<SyscallArg>,
+    #[serde(default)]
+    pub errno_ret: Option<u32>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallArg {
+    pub index: u32,
+    pub value: u64,
+    #[serde(default)]
+    pub value_two: u64,
+    pub op: String
+}
+
+/// Syscalls the built-in profile refuses with EPERM. Besides the mount
+/// syscalls, the new mount API and namespace changes are refused too, they'd
+/// give a way around the mount block with CAP_SYS_ADMIN.
+const DEFAULT_BLOCKED: [&str; 42] = [
+    "acct", "add_key", "bpf", "clock_adjtime", "clock_settime", "create_module", "delete_module",
+    "finit_module", "fsconfig", "fsmount", "fsopen", "fspick", "get_kernel_syms", "init_module",
+    "ioperm", "iopl", "kcmp", "kexec_file_load", "kexec_load", "keyctl", "lookup_dcookie", "mount",
+    "mount_setattr", "move_mount", "move_pages", "name_to_handle_at", "nfsservctl", "open_by_handle_at",
+    "open_tree", "perf_event_open", "pivot_root", "quotactl", "reboot", "request_key", "setns",
+    "settimeofday", "swapoff", "swapon", "umount2", "unshare", "uselib", "userfaultfd",
+];
+
+pub fn default_profile() -> Profile {
+    Profile {
+        default_action: "SCMP_ACT_ALLOW".to_string(),
+        default_errno_ret: None,
+        architectures: Vec::new(),
+        arch_map: Vec::new(),
+        syscalls: vec![SyscallRule {
+            names: DEFAULT_BLOCKED.iter().map(|s| s.to_string()).collect(),
+            name: None,
+            action: "SCMP_ACT_ERRNO".to_string(),
+            args: Vec::new(),
+            errno_ret: Some(EPERM as u32)
+        }]
+    }
+}
+
+pub fn load_profile(path: &Path) -> Result<Profile, String> {
+    let f = File::open(path).map_err(|e| format!("Can't open seccomp profile {:?} : {}", path, e))?;
+    serde_json::from_reader(f).map_err(|e| format!("Invalid seccomp profile {:?} : {}", path, e))
+}
+
+// classic BPF encoding, see linux/filter.h and linux/seccomp.h
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_ALU_AND_K: u16 = 0x54;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_JGT_K: u16 = 0x25;
+const BPF_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x80000000;
+const SECCOMP_RET_KILL_THREAD: u32 = 0x00000000;
+const SECCOMP_RET_TRAP: u32 = 0x00030000;
+const SECCOMP_RET_ERRNO: u32 = 0x00050000;
+const SECCOMP_RET_TRACE: u32 = 0x7ff00000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff0000;
+
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_DATA_ARGS: u32 = 16;
+
+const PR_SET_SECCOMP: c_int = 22;
+const SECCOMP_MODE_FILTER: c_ulong = 2;
+
+#[cfg(target_arch = "x86_64")]
+const NATIVE_ARCH: (&str, u32) = ("SCMP_ARCH_X86_64", 0xc000003e);
+#[cfg(target_arch = "aarch64")]
+const NATIVE_ARCH: (&str, u32) = ("SCMP_ARCH_AARCH64", 0xc00000b7);
+
+/// x32 syscalls run under the x86_64 audit arch with this bit set in the number.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x40000000;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter
+}
+
+/// Where a conditional jump goes before the rule block is laid out.
+#[derive(Clone, Copy)]
+enum Jump {
+    Skip(usize),
+    Pass,
+    Fail
+}
+
+struct Insn {
+    code: u16,
+    jt: Jump,
+    jf: Jump,
+    k: u32
+}
+
+fn stmt(code: u16, k: u32) -> Insn {
+    Insn { code: code, jt: Jump::Skip(0), jf: Jump::Skip(0), k: k }
+}
+
+fn jump(code: u16, k: u32, jt: Jump, jf: Jump) -> Insn {
+    Insn { code: code, jt: jt, jf: jf, k: k }
+}
+
+fn action_value(action: &str, errno_ret: Option<u32>) -> Result<u32, String> {
+    Ok(match action {
+        "SCMP_ACT_ALLOW" => SECCOMP_RET_ALLOW,
+        "SCMP_ACT_ERRNO" => SECCOMP_RET_ERRNO | (errno_ret.unwrap_or(EPERM as u32) & 0xffff),
+        "SCMP_ACT_KILL" | "SCMP_ACT_KILL_THREAD" => SECCOMP_RET_KILL_THREAD,
+        "SCMP_ACT_KILL_PROCESS" => SECCOMP_RET_KILL_PROCESS,
+        "SCMP_ACT_TRAP" => SECCOMP_RET_TRAP,
+        "SCMP_ACT_TRACE" => SECCOMP_RET_TRACE | (errno_ret.unwrap_or(0) & 0xffff),
+        "SCMP_ACT_LOG" => SECCOMP_RET_LOG,
+        _ => return Err(format!("Unknown seccomp action {:?}", action)),
+    })
+}
+
+/// Compare one 64-bit syscall argument as two 32-bit halves; on success
+/// control falls through past the comparison, on failure it jumps to `Fail`.
+fn compile_arg(arg: &SyscallArg) -> Result<Vec<Insn>, String> {
+    if arg.index > 5 {
+        return Err(format!("Invalid seccomp argument index {}", arg.index));
+    }
+    // little endian: the low word comes first
+    let lo_off = SECCOMP_DATA_ARGS + arg.index * 8;
+    let hi_off = lo_off + 4;
+    let (v_hi, v_lo) = ((arg.value >> 32) as u32, arg.value as u32);
+    let mut insns = vec![stmt(BPF_LD_W_ABS, hi_off)];
+    match &arg.op[..] {
+        "SCMP_CMP_EQ" => {
+            insns.push(jump(BPF_JEQ_K, v_hi, Jump::Skip(0), Jump::Fail));
+            insns.push(stmt(BPF_LD_W_ABS, lo_off));
+            insns.push(jump(BPF_JEQ_K, v_lo, Jump::Pass, Jump::Fail));
+        }
+        "SCMP_CMP_NE" => {
+            insns.push(jump(BPF_JEQ_K, v_hi, Jump::Skip(0), Jump::Pass));
+            insns.push(stmt(BPF_LD_W_ABS, lo_off));
+            insns.push(jump(BPF_JEQ_K, v_lo, Jump::Fail, Jump::Pass));
+        }
+        "SCMP_CMP_MASKED_EQ" => {
+            let (e_hi, e_lo) = ((arg.value_two >> 32) as u32, arg.value_two as u32);
+            insns.push(stmt(BPF_ALU_AND_K, v_hi));
+            insns.push(jump(BPF_JEQ_K, e_hi, Jump::Skip(0), Jump::Fail));
+            insns.push(stmt(BPF_LD_W_ABS, lo_off));
+            insns.push(stmt(BPF_ALU_AND_K, v_lo));
+            insns.push(jump(BPF_JEQ_K, e_lo, Jump::Pass, Jump::Fail));
+        }
+        "SCMP_CMP_GT" | "SCMP_CMP_GE" => {
+            insns.push(jump(BPF_JGT_K, v_hi, Jump::Pass, Jump::Skip(0)));
+            insns.push(jump(BPF_JEQ_K, v_hi, Jump::Skip(0), Jump::Fail));
+            insns.push(stmt(BPF_LD_W_ABS, lo_off));
+            let code = if arg.op == "SCMP_CMP_GT" { BPF_JGT_K } else { BPF_JGE_K };
+            insns.push(jump(code, v_lo, Jump::Pass, Jump::Fail));
+        }
+        "SCMP_CMP_LT" | "SCMP_CMP_LE" => {
+            insns.push(jump(BPF_JGT_K, v_hi, Jump::Fail, Jump::Skip(0)));
+            insns.push(jump(BPF_JEQ_K, v_hi, Jump::Skip(0), Jump::Pass));
+            insns.push(stmt(BPF_LD_W_ABS, lo_off));
+            let code = if arg.op == "SCMP_CMP_LT" { BPF_JGE_K } else { BPF_JGT_K };
+            insns.push(jump(code, v_lo, Jump::Fail, Jump::Pass));
+        }
+        _ => return Err(format!("Unknown seccomp operator {:?}", arg.op)),
+    }
+    let len = insns.len();
+    for (i, ins) in insns.iter_mut().enumerate() {
+        let pass = |j: Jump| if let Jump::Pass = j { Jump::Skip(len - i - 1) } else { j };
+        ins.jt = pass(ins.jt);
+        ins.jf = pass(ins.jf);
+    }
+    Ok(insns)
+}
+
+/// Lay out a rule block whose last instruction is its RET: `Fail` jumps
+/// past that RET to the next rule. Offsets must fit the 8 bits classic BPF allows.
+fn resolve(block: Vec<Insn>) -> Result<Vec<SockFilter>, String> {
+    let len = block.len();
+    let target = |i: usize, j: Jump| -> Result<u8, String> {
+        let off = match j {
+            Jump::Skip(n) => n,
+            Jump::Pass => return Err("unresolved seccomp jump".to_string()),
+            Jump::Fail => len - i - 1,
+        };
+        if off > 255 {
+            return Err("seccomp rule too large".to_string());
+        }
+        Ok(off as u8)
+    };
+    let mut out = Vec::with_capacity(len);
+    for (i, ins) in block.iter().enumerate() {
+        out.push(SockFilter { code: ins.code, jt: target(i, ins.jt)?, jf: target(i, ins.jf)?, k: ins.k });
+    }
+    Ok(out)
+}
+
+fn compile(profile: &Profile) -> Result<Vec<SockFilter>, String> {
+    let (native_name, native_audit) = NATIVE_ARCH;
+    let listed = profile.architectures.iter().chain(profile.arch_map.iter().map(|m| &m.architecture));
+    let mut listed = listed.peekable();
+    if listed.peek().is_some() && !listed.any(|a| a == native_name) {
+        return Err(format!("seccomp profile does not support architecture {}", native_name));
+    }
+
+    let mut prog = vec![
+        // syscall numbers are only meaningful for the native architecture
+        SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: SECCOMP_DATA_ARCH },
+        SockFilter { code: BPF_JEQ_K, jt: 1, jf: 0, k: native_audit },
+        SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_KILL_PROCESS },
+    ];
+    // an x32 number would match none of the rules below and reach the default
+    #[cfg(target_arch = "x86_64")]
+    prog.extend_from_slice(&[
+        SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: SECCOMP_DATA_NR },
+        SockFilter { code: BPF_JGE_K, jt: 0, jf: 1, k: X32_SYSCALL_BIT },
+        SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_KILL_PROCESS },
+    ]);
+    for rule in &profile.syscalls {
+        // defaultErrnoRet only applies to the default action, like in docker
+        let action = action_value(&rule.action, rule.errno_ret)?;
+        let names = rule.names.iter().chain(rule.name.iter());
+        for name in names {
+            let nr = match syscall_number(name) {
+                Some(nr) => nr,
+                None => {
+                    debug!("Skipping seccomp rule for unknown syscall {}", name);
+                    continue;
+                }
+            };
+            // load the number, compare, check every argument, then return the action
+            let mut block = vec![
+                stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+                jump(BPF_JEQ_K, nr as u32, Jump::Skip(0), Jump::Fail),
+            ];
+            for arg in &rule.args {
+                block.extend(compile_arg(arg)?);
+            }
+            block.push(stmt(BPF_RET_K, action));
+            prog.extend(resolve(block)?);
+        }
+    }
+    let default = action_value(&profile.default_action, profile.default_errno_ret)?;
+    prog.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: default });
+    Ok(prog)
+}
+
+/// Install the profile on the calling process; it is inherited across exec.
+pub fn install_profile(profile: &Profile, no_new_privs: bool) -> Result<(), String> {
+    let filter = compile(profile)?;
+    if filter.len() > 4096 {
+        return Err(format!("seccomp filter has {} instructions, the kernel allows 4096", filter.len()));
+    }
+    if no_new_privs {
+        set_no_new_privs()?;
+    }
+    let prog = SockFprog { len: filter.len() as u16, filter: filter.as_ptr() };
+    if unsafe { prctl(PR_SET_SECCOMP, SECCOMP_MODE_FILTER, &prog as *const SockFprog) } != 0 {
+        return Err(format!("Can't install seccomp filter : {}", IoError::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insn(code: u16, jt: u8, jf: u8, k: u32) -> SockFilter {
+        SockFilter { code: code, jt: jt, jf: jf, k: k }
+    }
+
+    fn profile(json: &str) -> Profile {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// The instructions every filter starts with, before the first rule.
+    fn prologue() -> Vec<SockFilter> {
+        let prog = compile(&profile(r#"{"defaultAction": "SCMP_ACT_ALLOW"}"#)).unwrap();
+        prog[..prog.len() - 1].to_vec()
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn checks_arch_and_rejects_x32() {
+        let prog = compile(&profile(r#"{"defaultAction": "SCMP_ACT_ALLOW"}"#)).unwrap();
+        assert_eq!(prog, vec![
+            insn(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_ARCH),
+            insn(BPF_JEQ_K, 1, 0, 0xc000003e),
+            insn(BPF_RET_K, 0, 0, SECCOMP_RET_KILL_PROCESS),
+            insn(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_NR),
+            insn(BPF_JGE_K, 0, 1, X32_SYSCALL_BIT),
+            insn(BPF_RET_K, 0, 0, SECCOMP_RET_KILL_PROCESS),
+            insn(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW),
+        ]);
+    }
+
+    #[test]
+    fn rule_without_args() {
+        let prog = compile(&profile(r#"{
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [{"names": ["mount"], "action": "SCMP_ACT_ERRNO", "errnoRet": 38}]
+        }"#)).unwrap();
+        let base = prologue().len();
+        let nr = syscall_number("mount").unwrap() as u32;
+        assert_eq!(prog[base..].to_vec(), vec![
+            insn(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_NR),
+            insn(BPF_JEQ_K, 0, 1, nr),
+            insn(BPF_RET_K, 0, 0, SECCOMP_RET_ERRNO | 38),
+            insn(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW),
+        ]);
+    }
+
+    #[test]
+    fn rule_with_arg_condition() {
+        let prog = compile(&profile(r#"{
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "syscalls": [{
+                "names": ["personality"],
+                "action": "SCMP_ACT_ALLOW",
+                "args": [{"index": 0, "value": 8, "op": "SCMP_CMP_EQ"}]
+            }]
+        }"#)).unwrap();
+        let base = prologue().len();
+        let nr = syscall_number("personality").unwrap() as u32;
+        assert_eq!(prog[base..].to_vec(), vec![
+            insn(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_NR),
+            insn(BPF_JEQ_K, 0, 5, nr),
+            insn(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_ARGS + 4),
+            insn(BPF_JEQ_K, 0, 3, 0),
+            insn(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_ARGS),
+            insn(BPF_JEQ_K, 0, 1, 8),
+            insn(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW),
+            insn(BPF_RET_K, 0, 0, SECCOMP_RET_ERRNO | EPERM as u32),
+        ]);
+    }
+
+    #[test]
+    fn masked_eq_compares_both_halves() {
+        let arg = SyscallArg { index: 1, value: 0x1_0000_00ff, value_two: 0x1_0000_0010, op: "SCMP_CMP_MASKED_EQ".to_string() };
+        let block = resolve({
+            let mut block = compile_arg(&arg).unwrap();
+            block.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+            block
+        }).unwrap();
+        let lo = SECCOMP_DATA_ARGS + 8;
+        assert_eq!(block, vec![
+            insn(BPF_LD_W_ABS, 0, 0, lo + 4),
+            insn(BPF_ALU_AND_K, 0, 0, 1),
+            insn(BPF_JEQ_K, 0, 4, 1),
+            insn(BPF_LD_W_ABS, 0, 0, lo),
+            insn(BPF_ALU_AND_K, 0, 0, 0xff),
+            insn(BPF_JEQ_K, 0, 1, 0x10),
+            insn(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW),
+        ]);
+    }
+
+    #[test]
+    fn invalid_arg_is_an_error() {
+        let arg = |index, op: &str| SyscallArg { index: index, value: 0, value_two: 0, op: op.to_string() };
+        assert!(compile_arg(&arg(6, "SCMP_CMP_EQ")).is_err());
+        assert!(compile_arg(&arg(0, "SCMP_CMP_BOGUS")).is_err());
+    }
+
+    #[test]
+    fn unknown_syscalls_are_skipped() {
+        let prog = compile(&profile(r#"{
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [{"names": ["no_such_syscall"], "action": "SCMP_ACT_ERRNO"}]
+        }"#)).unwrap();
+        assert_eq!(prog.len(), prologue().len() + 1);
+    }
+
+    #[test]
+    fn parses_docker_profile() {
+        let p = profile(r#"{
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "defaultErrnoRet": 1,
+            "archMap": [{"architecture": "SCMP_ARCH_X86_64", "subArchitectures": ["SCMP_ARCH_X86", "SCMP_ARCH_X32"]}],
+            "syscalls": [
+                {"names": ["read", "write"], "action": "SCMP_ACT_ALLOW"},
+                {"name": "clone", "action": "SCMP_ACT_ALLOW",
+                 "args": [{"index": 0, "value": 2114060288, "valueTwo": 0, "op": "SCMP_CMP_MASKED_EQ"}]}
+            ]
+        }"#);
+        assert_eq!(p.default_errno_ret, Some(1));
+        assert_eq!(p.arch_map[0].sub_architectures, vec!["SCMP_ARCH_X86", "SCMP_ARCH_X32"]);
+        assert_eq!(p.syscalls[0].names, vec!["read", "write"]);
+        assert_eq!(p.syscalls[1].name, Some("clone".to_string()));
+        assert_eq!(p.syscalls[1].args[0].value, 2114060288);
+        assert_eq!(p.syscalls[1].args[0].op, "SCMP_CMP_MASKED_EQ");
+    }
+
+    #[test]
+    fn foreign_architecture_is_an_error() {
+        assert!(compile(&profile(r#"{"defaultAction": "SCMP_ACT_ALLOW", "architectures": ["SCMP_ARCH_PPC64"]}"#)).is_err());
+    }
+
+    #[test]
+    fn action_values() {
+        assert_eq!(action_value("SCMP_ACT_ERRNO", None).unwrap(), SECCOMP_RET_ERRNO | EPERM as u32);
+        assert_eq!(action_value("SCMP_ACT_ERRNO", Some(38)).unwrap(), SECCOMP_RET_ERRNO | 38);
+        assert_eq!(action_value("SCMP_ACT_KILL", None).unwrap(), SECCOMP_RET_KILL_THREAD);
+        assert_eq!(action_value("SCMP_ACT_TRACE", Some(7)).unwrap(), SECCOMP_RET_TRACE | 7);
+        assert!(action_value("SCMP_ACT_BOGUS", None).is_err());
+    }
+
+    #[test]
+    fn default_profile_blocks_mount_api() {
+        let blocked = &default_profile().syscalls[0].names;
+        for name in &["mount", "fsopen", "fsmount", "move_mount", "open_tree", "mount_setattr", "unshare", "setns"] {
+            assert!(blocked.iter().any(|b| b == name), "{} is not blocked", name);
+        }
+    }
+}
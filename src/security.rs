@@ -0,0 +1,83 @@
+use std::io::Error as IoError;
+use std::path::Path;
+use libc::{c_int, prctl};
+use capabilities::DEFAULT_CAPABILITIES;
+use seccomp::{Profile, default_profile, load_profile};
+
+/// Paths hidden from the container, the same set docker masks by default.
+pub const DEFAULT_MASKED_PATHS: [&str; 11] = [
+    "/proc/asound",
+    "/proc/acpi",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/sys/firmware",
+    "/sys/devices/virtual/powercap",
+];
+
+/// Paths the container may read but never write.
+pub const DEFAULT_READONLY_PATHS: [&str; 5] = [
+    "/proc/bus",
+    "/proc/fs",
+    "/proc/irq",
+    "/proc/sys",
+    "/proc/sysrq-trigger",
+];
+
+/// Settings given with `--security-opt`, on top of the defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SecurityOptions {
+    pub masked_paths: Vec<String>,
+    pub readonly_paths: Vec<String>,
+    pub capabilities: Vec<String>,
+    // None runs the container unconfined
+    pub seccomp: Option<Profile>,
+    pub no_new_privileges: bool
+}
+
+impl SecurityOptions {
+    pub fn parse(opts: &[String]) -> Result<SecurityOptions, String> {
+        let mut security = SecurityOptions {
+            masked_paths: DEFAULT_MASKED_PATHS.iter().map(|p| p.to_string()).collect(),
+            readonly_paths: DEFAULT_READONLY_PATHS.iter().map(|p| p.to_string()).collect(),
+            capabilities: DEFAULT_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            seccomp: Some(default_profile()),
+            no_new_privileges: false
+        };
+        for opt in opts {
+            let mut kv = opt.splitn(2, '=');
+            let (key, value) = (kv.next().unwrap_or(""), kv.next().unwrap_or(""));
+            match key {
+                "masked-path" | "readonly-path" if !value.starts_with('/') => {
+                    return Err(format!("Invalid --security-opt {:?}, path must be absolute", opt));
+                }
+                "masked-path" => security.masked_paths.push(value.to_string()),
+                "readonly-path" => security.readonly_paths.push(value.to_string()),
+                "seccomp" if value == "unconfined" => security.seccomp = None,
+                "seccomp" if value != "" => security.seccomp = Some(load_profile(Path::new(value))?),
+                "no-new-privileges" => security.no_new_privileges = match value {
+                    "" | "true" => true,
+                    "false" => false,
+                    _ => return Err(format!("Invalid --security-opt {:?}, expected true or false", opt)),
+                },
+                _ => return Err(format!("Invalid --security-opt {:?}", opt)),
+            }
+        }
+        Ok(security)
+    }
+}
+
+const PR_SET_NO_NEW_PRIVS: c_int = 38;
+
+/// Stop execve from granting privileges, setuid bits and file capabilities are ignored.
+pub fn set_no_new_privs() -> Result<(), String> {
+    if unsafe { prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(format!("Can't set no_new_privs : {}", IoError::last_os_error()));
+    }
+    Ok(())
+}
@@ -0,0 +1,308 @@
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::Error as IoError;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::process::exit;
+use std::thread::{sleep, JoinHandle};
+use std::time::Instant;
+use unshare::Child;
+use libc::{fork, setsid, getpid, waitpid, dup2, pipe2, _exit, O_CLOEXEC, SIGKILL};
+use cgroup::CgroupManager;
+use config::info_location;
+use console::spawn_console_server;
+use container::{ContainerInfo, new_parent_process, wait_for_init, delete_work_space, delete_container_info};
+use container::{record_container_info, get_container_info, update_container_info, RUNNING, RESTARTING, EXIT};
+use init_config::{InitConfig, InitError, SyncMessage, write_init_config, write_sync_message, read_sync_message};
+use oom::OomWatcher;
+use restart::Backoff;
+use health::{HealthChecker, HealthState};
+use userns::IdMappings;
+use now;
+
+const SHIM_LOG: &str = "shim.log";
+
+/// Everything the monitor needs to start and look after one container.
+pub struct ShimConfig {
+    pub info: ContainerInfo,
+    pub init: InitConfig,
+    pub tty: bool,
+    /// `run -d`, nobody attaches to the first start.
+    pub detach: bool,
+    pub image_name: String,
+    pub id_mappings: Option<IdMappings>
+}
+
+/// The CLI's end of the monitor's report pipe.
+pub struct Shim {
+    report: File
+}
+
+impl Shim {
+    /// Block until the container first exits and return its exit code. The
+    /// monitor reports it before an `--rm` container's state is deleted.
+    pub fn wait(mut self) -> Result<i32, String> {
+        match read_sync_message(&mut self.report)? {
+            Some(SyncMessage::Exit { code }) => Ok(code),
+            Some(_) => Err("unexpected message from monitor".to_string()),
+            None => Err("monitor exited without reporting the exit code".to_string()),
+        }
+    }
+}
+
+/// Start the container under a monitor that double-forks away from the
+/// CLI, so it neither dies with the CLI nor leaves a zombie behind. Returns
+/// once the container's command is running or failed to start.
+pub fn start_shim(config: ShimConfig) -> Result<Shim, String> {
+    let mut fds = [0; 2];
+    if unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) } != 0 {
+        return Err(format!("Can't create pipe : {}", IoError::last_os_error()));
+    }
+    let (mut report_reader, report_writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { fork() } {
+        -1 => return Err(format!("Can't fork monitor : {}", IoError::last_os_error())),
+        0 => {
+            drop(report_reader);
+            unsafe { setsid() };
+            match unsafe { fork() } {
+                -1 => unsafe { _exit(1) },
+                0 => exit(run_shim(config, report_writer)),
+                // the monitor is reparented to init, we only bridged the gap
+                _ => unsafe { _exit(0) },
+            }
+        }
+        pid => {
+            drop(report_writer);
+            unsafe { waitpid(pid, ::std::ptr::null_mut(), 0) };
+        }
+    }
+
+    match read_sync_message(&mut report_reader)? {
+        Some(SyncMessage::Ready) => Ok(Shim { report: report_reader }),
+        Some(SyncMessage::Error(e)) => Err(e.to_string()),
+        Some(SyncMessage::Exit { .. }) => Err("monitor reported an exit before the start".to_string()),
+        None => Err("monitor exited without reporting a reason".to_string()),
+    }
+}
+
+fn run_shim(config: ShimConfig, mut report: File) -> i32 {
+    let name = config.info.name.clone();
+    // the CLI's terminal is only borrowed, our own output goes to a log
+    let dir = format!("{}/{}", info_location(), name);
+    let log = create_dir_all(&dir).and_then(|_| {
+        OpenOptions::new().create(true).append(true).open(format!("{}/{}", dir, SHIM_LOG))
+    });
+    match (log, OpenOptions::new().read(true).open("/dev/null")) {
+        (Ok(log), Ok(null)) => unsafe {
+            dup2(null.as_raw_fd(), 0);
+            dup2(log.as_raw_fd(), 1);
+            dup2(log.as_raw_fd(), 2);
+        },
+        (Err(e), _) | (_, Err(e)) => {
+            report_error(&mut report, "monitor", format!("Open monitor log error {}", e));
+            return 1;
+        }
+    }
+
+    match monitor(config, report) {
+        Ok(code) => code,
+        Err(e) => {
+            error!("Monitor container {} error {}", name, e);
+            1
+        }
+    }
+}
+
+/// A started container and what watches it.
+struct Running {
+    child: Child,
+    oom_watcher: Option<OomWatcher>,
+    health_checker: Option<HealthChecker>,
+    console_server: JoinHandle<()>
+}
+
+/// How a container ended.
+struct Exit {
+    code: i32,
+    oom_killed: bool,
+    success: bool,
+    /// Killed by its health checker, to be started again.
+    unhealthy: bool
+}
+
+fn monitor(config: ShimConfig, mut report: File) -> Result<i32, String> {
+    let mut info = config.info.clone();
+    let cgroup_manager = if info.cgroup_path != "" {
+        Some(CgroupManager::new_cgroup_manager(Path::new(&info.cgroup_path)))
+    } else {
+        None
+    };
+    if let Some(ref cgroup_manager) = cgroup_manager {
+        if let Err(e) = cgroup_manager.set(&info.resources) {
+            let e = format!("Set cgroup limits error {}", e);
+            report_error(&mut report, "cgroup", e.clone());
+            cleanup(&info.name, Some(cgroup_manager), 1, false, false, info.auto_remove);
+            return Err(e);
+        }
+    }
+
+    // only the first start and exit are reported, the CLI is long gone for restarts
+    let mut report = Some(report);
+    let mut backoff = Backoff::new();
+    loop {
+        let started_at = Instant::now();
+        let exit = match start_container(&config, &mut info, cgroup_manager.as_ref()) {
+            Ok(running) => {
+                if let Some(ref mut report) = report {
+                    write_sync_message(report, &SyncMessage::Ready)?;
+                }
+                wait_container(running, &info.name)?
+            }
+            Err(e) => {
+                if let Some(mut report) = report.take() {
+                    report_error(&mut report, "start", e.clone());
+                    cleanup(&info.name, cgroup_manager.as_ref(), 1, false, false, info.auto_remove);
+                    return Err(e);
+                }
+                error!("Restart container {} error {}", info.name, e);
+                Exit { code: 1, oom_killed: false, success: false, unhealthy: false }
+            }
+        };
+        let (code, oom_killed, success) = (exit.code, exit.oom_killed, exit.success);
+        // before --rm deletes the state, a detached CLI just isn't reading
+        if let Some(mut report) = report.take() {
+            let _ = write_sync_message(&mut report, &SyncMessage::Exit { code: code });
+        }
+
+        // `mydocker stop` marks the container before signalling it
+        let manually_stopped = get_container_info(&info.name).map(|i| i.manually_stopped).unwrap_or(false);
+        let restart = if exit.unhealthy {
+            !manually_stopped
+        } else {
+            info.restart_policy.should_restart(code, info.restart_count, manually_stopped)
+        };
+        if !restart {
+            cleanup(&info.name, cgroup_manager.as_ref(), code, oom_killed, success, info.auto_remove);
+            return Ok(code);
+        }
+        let delay = backoff.next(started_at.elapsed());
+        info!("Restarting container {} in {:?} after exit code {}", info.name, delay, code);
+        update_container_info(&info.name, |info| {
+            info.status = RESTARTING.to_string();
+            info.exit_code = Some(code);
+            info.oom_killed = oom_killed;
+            info.finish_time = now();
+        })?;
+        sleep(delay);
+        // stopped while waiting to restart
+        if get_container_info(&info.name).map(|i| i.manually_stopped).unwrap_or(false) {
+            cleanup(&info.name, cgroup_manager.as_ref(), code, oom_killed, success, info.auto_remove);
+            return Ok(code);
+        }
+        info.restart_count += 1;
+    }
+}
+
+fn start_container(config: &ShimConfig, info: &mut ContainerInfo,
+                   cgroup_manager: Option<&CgroupManager>) -> Result<Running, String> {
+    let (mut child, mut config_writer, mut sync_reader, console) =
+        new_parent_process(config.tty, &info.name, &config.image_name, config.id_mappings.as_ref())?;
+    info.pid = child.pid();
+    info.monitor_pid = unsafe { getpid() };
+    info.status = RUNNING.to_string();
+    // every start is judged afresh
+    info.health = info.healthcheck.as_ref().map(|_| HealthState::starting());
+    let mut started = if info.restart_count == 0 {
+        record_container_info(info)
+    } else {
+        let (pid, restart_count, health) = (info.pid, info.restart_count, info.health.clone());
+        update_container_info(&info.name, |info| {
+            info.pid = pid;
+            info.status = RUNNING.to_string();
+            info.restart_count = restart_count;
+            info.health = health;
+        })
+    };
+
+    let mut oom_watcher = None;
+    if let Some(cgroup_manager) = cgroup_manager {
+        // a container outside its cgroups would run without limits or device policy
+        started = started.and_then(|_| cgroup_manager.apply(info.pid)
+            .map_err(|e| format!("Join cgroup {:?} error {}", cgroup_manager.path, e)));
+        if started.is_ok() {
+            oom_watcher = match OomWatcher::new(cgroup_manager.path) {
+                Ok(watcher) => Some(watcher),
+                Err(e) => {
+                    warn!("Can't watch container for OOM events: {}", e);
+                    None
+                }
+            };
+        }
+    }
+
+    started = started
+        .and_then(|_| write_init_config(&mut config_writer, &config.init))
+        .and_then(|_| {
+            drop(config_writer);
+            wait_for_init(&mut sync_reader)
+        });
+    let replay = !config.detach && info.restart_count == 0;
+    let console_server = match started.and_then(|_| spawn_console_server(&info.name, console, replay)) {
+        Ok(server) => server,
+        Err(e) => {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(e);
+        }
+    };
+    let health_checker = info.healthcheck.clone().map(|healthcheck| {
+        HealthChecker::spawn(&info.name, info.pid, healthcheck, &config.init)
+    });
+    Ok(Running { child: child, oom_watcher: oom_watcher, health_checker: health_checker, console_server: console_server })
+}
+
+fn wait_container(mut running: Running, name: &str) -> Result<Exit, String> {
+    let status = running.child.wait().map_err(|e| format!("Wait container error: {}", e))?;
+    let unhealthy = running.health_checker.map(|checker| checker.stop()).unwrap_or(false);
+    // the console drains what the container wrote before it went away
+    if running.console_server.join().is_err() {
+        warn!("Console server of {} panicked", name);
+    }
+    let code = status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0));
+    let sigkilled = status.signal() == Some(SIGKILL);
+    let oom_killed = running.oom_watcher.map(|w| w.oom_killed(sigkilled)).unwrap_or(false);
+    if oom_killed {
+        error!("The OOM killer killed a process of container {}", name);
+    }
+    Ok(Exit { code: code, oom_killed: oom_killed, success: status.success(), unhealthy: unhealthy })
+}
+
+fn report_error(report: &mut File, stage: &str, message: String) {
+    let err = InitError::new(stage, message);
+    if let Err(e) = write_sync_message(report, &SyncMessage::Error(err)) {
+        error!("Report start error to CLI failed: {}", e);
+    }
+}
+
+fn cleanup(name: &str, cgroup_manager: Option<&CgroupManager>, code: i32,
+           oom_killed: bool, success: bool, auto_remove: bool) {
+    if let Err(e) = update_container_info(name, |info| {
+        info.status = EXIT.to_string();
+        info.exit_code = Some(code);
+        info.oom_killed = oom_killed;
+        info.finish_time = now();
+    }) {
+        error!("Update container info error {}", e);
+    }
+    if let Some(cgroup_manager) = cgroup_manager {
+        if let Err(e) = cgroup_manager.destroy() {
+            error!("Destroy cgroup {:?} error {}", cgroup_manager.path, e);
+        }
+    }
+    if success || auto_remove {
+        delete_work_space(name.to_string());
+    }
+    if auto_remove {
+        delete_container_info(name);
+    }
+}
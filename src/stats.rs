@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Instant;
+use libc::pid_t;
+use subsystem::{get_cgroup_path, read_cgroup_file, is_cgroup_v2};
+
+/// Raw counters read back from a container's cgroups and network namespace.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CgroupStats {
+    pub cpu_usage_ns: u64,
+    pub memory_usage: u64,
+    pub memory_limit: Option<u64>,
+    pub pids_current: u64,
+    pub blkio_read_bytes: u64,
+    pub blkio_write_bytes: u64
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct NetworkStats {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64
+}
+
+/// One sample of a container, with percentages computed against the previous sample.
+#[derive(Serialize, Debug, Clone)]
+pub struct ContainerStats {
+    pub name: String,
+    pub cpu_percent: f64,
+    pub memory_usage: u64,
+    pub memory_limit: Option<u64>,
+    pub memory_percent: f64,
+    pub pids: u64,
+    pub block_read: u64,
+    pub block_write: u64,
+    pub net_rx: u64,
+    pub net_tx: u64,
+    #[serde(skip)]
+    pub sampled_at: Option<Instant>,
+    #[serde(skip)]
+    pub cpu_usage_ns: u64
+}
+
+fn parse_u64(value: &str, what: &str) -> Result<u64, String> {
+    value.trim().parse().map_err(|e| format!("Invalid {} {:?} : {}", what, value, e))
+}
+
+fn read_u64(subsys_cgroup_path: &Path, file: &str) -> Result<u64, String> {
+    parse_u64(&read_cgroup_file(subsys_cgroup_path, file)?, file)
+}
+
+/// Read usage counters for every controller from the cgroup at `cgroup_path`.
+pub fn read_cgroup_stats(cgroup_path: &Path) -> Result<CgroupStats, String> {
+    let mut stats = CgroupStats::default();
+    if is_cgroup_v2() {
+        let p = get_cgroup_path("memory", cgroup_path, false)?;
+        for line in read_cgroup_file(p, "cpu.stat")?.lines() {
+            let mut fields = line.split_whitespace();
+            if fields.next() == Some("usage_usec") {
+                stats.cpu_usage_ns = parse_u64(fields.next().unwrap_or(""), "usage_usec")? * 1000;
+            }
+        }
+        stats.memory_usage = read_u64(p, "memory.current")?;
+        let max = read_cgroup_file(p, "memory.max")?;
+        stats.memory_limit = if max == "max" { None } else { Some(parse_u64(&max, "memory.max")?) };
+        stats.pids_current = read_u64(p, "pids.current").unwrap_or(0);
+        for line in read_cgroup_file(p, "io.stat").unwrap_or_default().lines() {
+            for field in line.split_whitespace().skip(1) {
+                let mut kv = field.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some("rbytes"), Some(v)) => stats.blkio_read_bytes += parse_u64(v, "rbytes")?,
+                    (Some("wbytes"), Some(v)) => stats.blkio_write_bytes += parse_u64(v, "wbytes")?,
+                    _ => {}
+                }
+            }
+        }
+        return Ok(stats);
+    }
+
+    stats.cpu_usage_ns = read_u64(get_cgroup_path("cpuacct", cgroup_path, false)?, "cpuacct.usage")?;
+    let memory_path = get_cgroup_path("memory", cgroup_path, false)?;
+    stats.memory_usage = read_u64(memory_path, "memory.usage_in_bytes")?;
+    // an unlimited v1 cgroup reports a huge page-aligned number instead of "max"
+    let limit = read_u64(memory_path, "memory.limit_in_bytes")?;
+    stats.memory_limit = if limit >= i64::max_value() as u64 / 2 { None } else { Some(limit) };
+    if let Ok(pids_path) = get_cgroup_path("pids", cgroup_path, false) {
+        stats.pids_current = read_u64(pids_path, "pids.current").unwrap_or(0);
+    }
+    if let Ok(blkio_path) = get_cgroup_path("blkio", cgroup_path, false) {
+        for line in read_cgroup_file(blkio_path, "blkio.throttle.io_service_bytes").unwrap_or_default().lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                continue;
+            }
+            match fields[1] {
+                "Read" => stats.blkio_read_bytes += parse_u64(fields[2], "blkio read bytes")?,
+                "Write" => stats.blkio_write_bytes += parse_u64(fields[2], "blkio write bytes")?,
+                _ => {}
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Sum the interface counters of the network namespace `pid` lives in, skipping loopback.
+pub fn read_network_stats(pid: pid_t) -> Result<NetworkStats, String> {
+    let path = format!("/proc/{}/net/dev", pid);
+    let f = File::open(&path).map_err(|e| format!("Can't open {} : {}", path, e))?;
+    let mut stats = NetworkStats::default();
+    // the first two lines are headers
+    for line in BufReader::new(f).lines().skip(2) {
+        let line = line.map_err(|e| format!("Error reading {} : {}", path, e))?;
+        let mut parts = line.splitn(2, ':');
+        let iface = parts.next().unwrap_or("").trim();
+        let fields: Vec<&str> = parts.next().unwrap_or("").split_whitespace().collect();
+        if iface == "lo" || fields.len() < 9 {
+            continue;
+        }
+        stats.rx_bytes += parse_u64(fields[0], "rx bytes")?;
+        stats.tx_bytes += parse_u64(fields[8], "tx bytes")?;
+    }
+    Ok(stats)
+}
+
+impl ContainerStats {
+    /// Build a sample from fresh counters; `prev` is the last sample of the same container.
+    pub fn sample(name: &str, cgroup: CgroupStats, net: NetworkStats, prev: Option<&ContainerStats>) -> ContainerStats {
+        let now = Instant::now();
+        let cpu_percent = match prev.and_then(|p| p.sampled_at.map(|t| (t, p.cpu_usage_ns))) {
+            Some((then, prev_usage)) => {
+                let elapsed = now.duration_since(then);
+                let elapsed_ns = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+                if elapsed_ns == 0 {
+                    0.0
+                } else {
+                    cgroup.cpu_usage_ns.saturating_sub(prev_usage) as f64 / elapsed_ns as f64 * 100.0
+                }
+            }
+            None => 0.0,
+        };
+        let memory_percent = match cgroup.memory_limit {
+            Some(limit) if limit > 0 => cgroup.memory_usage as f64 / limit as f64 * 100.0,
+            _ => 0.0,
+        };
+        ContainerStats {
+            name: name.to_string(),
+            cpu_percent: cpu_percent,
+            memory_usage: cgroup.memory_usage,
+            memory_limit: cgroup.memory_limit,
+            memory_percent: memory_percent,
+            pids: cgroup.pids_current,
+            block_read: cgroup.blkio_read_bytes,
+            block_write: cgroup.blkio_write_bytes,
+            net_rx: net.rx_bytes,
+            net_tx: net.tx_bytes,
+            sampled_at: Some(now),
+            cpu_usage_ns: cgroup.cpu_usage_ns
+        }
+    }
+}
+
+pub fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", size, units[unit])
+}
@@ -1,14 +1,100 @@
-use std::fs::{File, Permissions};
-use std::fs::{remove_dir, set_permissions};
-use std::os::unix::fs::{PermissionsExt};
-use std::io::{BufReader, BufRead};
+use std::fs::{File, OpenOptions, Permissions};
+use std::fs::{remove_dir, set_permissions, metadata, create_dir_all, read_dir};
+use std::os::unix::fs::{PermissionsExt, FileTypeExt, MetadataExt};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf}
-use libc::pid_t;
+use std::thread::sleep;
+use std::time::Duration;
+use libc::{pid_t, major, minor};
+use mountinfo::self_mounts;
+use config::is_rootless;
+use cgroup::delegated_cgroup_root;
+use devices::{Device, default_allow_rules, attach_device_filter};
 
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct ResourceConfig {
     pub memory_limit: String,
+    pub memory_swap: String,
+    pub memory_reservation: String,
+    pub kernel_memory: String,
+    /// None leaves the cgroup's setting alone.
+    pub oom_kill_disable: Option<bool>,
+    pub memory_swappiness: String,
     pub cpu_share: String,
-    pub cpu_set: String
+    pub cpus: String,
+    pub cpu_set: String,
+    pub pids_limit: String,
+    pub blkio_weight: String,
+    pub device_read_bps: Vec<String>,
+    pub device_write_bps: Vec<String>,
+    pub device_read_iops: Vec<String>,
+    pub device_write_iops: Vec<String>,
+    pub devices: Vec<String>
+}
+
+impl ResourceConfig {
+    /// Check that the memory options make sense together before touching any cgroup.
+    pub fn validate(&self) -> Result<(), String> {
+        let memory = if self.memory_limit != "" { Some(parse_size(&self.memory_limit)?) } else { None };
+        if self.memory_swap != "" && self.memory_swap != "-1" {
+            let swap = parse_size(&self.memory_swap)?;
+            match memory {
+                None => return Err("--memory-swap requires --memory to be set".to_string()),
+                Some(m) if swap < m => return Err(format!(
+                    "--memory-swap {} should be larger than or equal to --memory {}", swap, m)),
+                _ => {}
+            }
+        }
+        if self.memory_reservation != "" {
+            let reservation = parse_size(&self.memory_reservation)?;
+            if let Some(m) = memory {
+                if reservation > m {
+                    return Err(format!(
+                        "--memory-reservation {} should be smaller than --memory {}", reservation, m));
+                }
+            }
+        }
+        if self.memory_swappiness != "" {
+            match self.memory_swappiness.parse::<i64>() {
+                Ok(v) if v >= 0 && v <= 100 => {}
+                _ => return Err(format!("Invalid --memory-swappiness {:?}, expected 0-100", self.memory_swappiness)),
+            }
+        }
+        if self.cpus != "" {
+            match self.cpus.parse::<f64>() {
+                Ok(v) if v > 0.0 => {}
+                _ => return Err(format!("Invalid --cpus {:?}, expected a positive number", self.cpus)),
+            }
+        }
+        if self.pids_limit != "" && self.pids_limit != "-1" {
+            self.pids_limit.parse::<u64>()
+                .map_err(|e| format!("Invalid --pids-limit {:?} : {}", self.pids_limit, e))?;
+        }
+        Ok(())
+    }
+
+    /// Overlay every option set in `other` on top of this config.
+    pub fn merge(&mut self, other: &ResourceConfig) {
+        fn overlay(mine: &mut String, theirs: &String) {
+            if *theirs != "" {
+                *mine = theirs.clone();
+            }
+        }
+        overlay(&mut self.memory_limit, &other.memory_limit);
+        overlay(&mut self.memory_swap, &other.memory_swap);
+        overlay(&mut self.memory_reservation, &other.memory_reservation);
+        overlay(&mut self.kernel_memory, &other.kernel_memory);
+        overlay(&mut self.memory_swappiness, &other.memory_swappiness);
+        overlay(&mut self.cpu_share, &other.cpu_share);
+        overlay(&mut self.cpus, &other.cpus);
+        overlay(&mut self.cpu_set, &other.cpu_set);
+        overlay(&mut self.pids_limit, &other.pids_limit);
+        overlay(&mut self.blkio_weight, &other.blkio_weight);
+        if other.oom_kill_disable.is_some() {
+            self.oom_kill_disable = other.oom_kill_disable;
+        }
+    }
 }
 
 pub trait Subsystem {
@@ -26,7 +112,8 @@ pub trait Subsystem {
     }
     fn apply(path: &Path, pid: pid_t) -> Result<_, String> {
         let subsys_cgroup_path = get_cgroup_path(self.name(), cgroup_path, false)?;
-        let p = subsys_cgroup_path.join(Path::new("tasks"));
+        // v2 has no tasks file, processes move through cgroup.procs
+        let p = subsys_cgroup_path.join(Path::new(if is_cgroup_v2() { "cgroup.procs" } else { "tasks" }));
         set_permissions(&p, Permissions::from_mode(0o644)).map_err(|e| format!("Error setting permissions: {}", e));
         let f = File::create(&p)?;
         f.write_all(pid.to_string().into_bytes()).map_err(|e| format!("set cgroup {} share fail {}", self.name(), e))?;
@@ -35,32 +122,30 @@ pub trait Subsystem {
     }
     fn remove(path: &Path) -> Result<_, String> {
         let subsys_cgroup_path = get_cgroup_path(self.name(), cgroup_path, false)?;
-        remove_dir(subsys_cgroup_path)?;
+        remove_cgroup_dir(subsys_cgroup_path)?;
         Ok(_)
     }
 }
 
-fn find_cgroup_mountpoint(subsystem: &Path) -> Result<&Path, String> {
-    let file = File::open(&Path::new("/proc/self/mountinfo"))
-        .map_err(|e| format!("Can't open /proc/self/mountinfo : {}", e))?;
-    let file = BufReader::new(file);
-    for line in file.lines() {
-        let line = line.map_err(|e| format!("Error reading requirements: {}", e))?;
-        let fields: Vec<&str> = line.split(" ").collect();
-        fields.last().expect("fields is empty.")?.split(",").for_each(|opt| {
-            if some(opt) == subsystem.to_str() {
-                fields[4]
-            }
-        })
-    }
+pub fn find_cgroup_mountpoint(subsystem: &Path) -> Result<PathBuf, String> {
+    let subsystem = subsystem.to_str().ok_or(format!("Invalid subsystem name {:?}", subsystem))?;
+    let mounts = self_mounts()?;
+    let v2 = is_cgroup_v2();
+    mounts.iter()
+        .find(|m| if v2 { m.fstype == "cgroup2" } else { m.fstype == "cgroup" && m.has_super_option(subsystem) })
+        .map(|m| m.mount_point.clone())
+        .ok_or(format!("Can't find cgroup mountpoint of subsystem {}", subsystem))
 }
 
 pub fn get_cgroup_path(subsystem: &Path, cgroup_path: &Path, auto_create: bool) -> Result<&Path, String> {
-    let cgroup_root = find_cgroup_mountpoint(subsystem);
+    let cgroup_root = find_cgroup_mountpoint(subsystem)?;
     let p = cgroup_root.join(cgroup_path);
     if p.exists() || auto_create {
         if p.exists() == false {
-            create_dir(p)?;
+            create_dir_all(&p).map_err(|e| format!("Can't create cgroup {:?} : {}", p, e))?;
+            if is_cgroup_v2() {
+                enable_v2_controllers(&cgroup_root, cgroup_path)?;
+            }
             return Ok(p);
         }
         return Ok(p);
@@ -68,19 +153,170 @@ pub fn get_cgroup_path(subsystem: &Path, cgroup_path: &Path, auto_create: bool)
     return Err("error create cgroup");
 }
 
+/// On cgroup v2 a controller is only usable in a cgroup when every ancestor
+/// delegates it through `cgroup.subtree_control`. Each level can only pass
+/// on what it got itself, listed in its own `cgroup.controllers`.
+fn enable_v2_controllers(cgroup_root: &Path, cgroup_path: &Path) -> Result<(), String> {
+    // a rootless user can only write from the subtree systemd delegated to it down
+    let delegated = if is_rootless() { delegated_cgroup_root().unwrap_or_default() } else { PathBuf::new() };
+    let below = cgroup_path.parent().unwrap_or(Path::new("")).strip_prefix(&delegated)
+        .map_err(|_| format!("Cgroup {:?} is outside the delegated subtree {:?}", cgroup_path, delegated))?;
+    let mut current = cgroup_root.join(&delegated);
+    let mut levels = vec![current.clone()];
+    for component in below.components() {
+        current.push(component);
+        levels.push(current.clone());
+    }
+    for level in &levels {
+        let controllers = read_cgroup_file(level, "cgroup.controllers")?;
+        if controllers == "" {
+            continue;
+        }
+        let enable: Vec<String> = controllers.split_whitespace().map(|c| format!("+{}", c)).collect();
+        write_cgroup_file(level, "cgroup.subtree_control", &enable.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Remove a cgroup and every cgroup nested below it, children first.
+/// A cgroup directory can only be removed with rmdir once it has no children
+/// and no processes left.
+pub fn remove_cgroup_dir(path: &Path) -> Result<(), String> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let entries = read_dir(path).map_err(|e| format!("Can't read cgroup {:?} : {}", path, e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Can't read cgroup {:?} : {}", path, e))?;
+        if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            remove_cgroup_dir(&entry.path())?;
+        }
+    }
+    remove_dir(path).map_err(|e| format!("Can't remove cgroup {:?} : {}", path, e))
+}
+
+pub fn is_cgroup_v2() -> bool {
+    Path::new("/sys/fs/cgroup/cgroup.controllers").exists()
+}
+
+pub fn read_cgroup_file(subsys_cgroup_path: &Path, file: &str) -> Result<String, String> {
+    let p = subsys_cgroup_path.join(file);
+    let mut content = String::new();
+    File::open(&p).and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| format!("Can't read {:?} : {}", p, e))?;
+    Ok(content.trim().to_string())
+}
+
+pub fn write_cgroup_file(subsys_cgroup_path: &Path, file: &str, value: &str) -> Result<(), String> {
+    let p = subsys_cgroup_path.join(file);
+    let mut f = OpenOptions::new().write(true).open(&p)
+        .map_err(|e| format!("Can't open {:?} : {}", p, e))?;
+    f.write_all(value.as_bytes()).map_err(|e| format!("Can't write {:?} to {:?} : {}", value, p, e))
+}
+
+/// Resolve a block device path such as `/dev/sda` to its `major:minor` pair.
+pub fn device_numbers(device: &Path) -> Result<(u32, u32), String> {
+    let meta = metadata(device).map_err(|e| format!("Can't stat device {:?} : {}", device, e))?;
+    if !meta.file_type().is_block_device() {
+        return Err(format!("{:?} is not a block device", device));
+    }
+    let rdev = meta.rdev();
+    Ok((major(rdev), minor(rdev)))
+}
+
+/// Parse a `<device-path>:<rate>` throttle argument into `major:minor` and
+/// rate, with `parse_rate` reading a byte size or a plain IO count.
+fn parse_device_rate(arg: &str, parse_rate: fn(&str) -> Result<u64, String>) -> Result<(String, u64), String> {
+    let pos = arg.rfind(':').ok_or(format!("Invalid device rate {:?}, expected <path>:<rate>", arg))?;
+    let (path, rate) = (&arg[..pos], &arg[pos + 1..]);
+    let (maj, min) = device_numbers(Path::new(path))?;
+    let rate = parse_rate(rate).map_err(|e| format!("Invalid rate in {:?} : {}", arg, e))?;
+    Ok((format!("{}:{}", maj, min), rate))
+}
+
+/// Parse an IO count such as `1000`, units don't apply to operations.
+fn parse_count(count: &str) -> Result<u64, String> {
+    count.trim().parse::<u64>().map_err(|e| format!("{:?} is not a valid count: {}", count, e))
+}
+
+/// Parse a size such as `1048576`, `512k`, `10m` or `1g` into bytes.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim().to_lowercase();
+    let (num, mult) = match size.chars().last() {
+        Some('k') => (&size[..size.len() - 1], 1 << 10),
+        Some('m') => (&size[..size.len() - 1], 1 << 20),
+        Some('g') => (&size[..size.len() - 1], 1 << 30),
+        Some('b') => (&size[..size.len() - 1], 1),
+        _ => (&size[..], 1),
+    };
+    let n = num.parse::<u64>().map_err(|e| format!("{:?} is not a valid size: {}", size, e))?;
+    n.checked_mul(mult).ok_or(format!("{:?} is too large", size))
+}
+
 pub struct CpuSubSystem {}
 
+const CPU_PERIOD: u64 = 100000;
+
 impl Subsystem for CpuSubSystem {
     pub fn name() -> String {
         "cpu".to_string()
     }
+
+    fn set(&self, cgroup_path: &Path, res: &ResourceConfig) -> Result<(), String> {
+        let subsys_cgroup_path = get_cgroup_path(Self::name(), cgroup_path, true)?;
+        let v2 = is_cgroup_v2();
+        if res.cpu_share != "" {
+            if v2 {
+                // map the v1 shares range [2, 262144] onto the v2 weight range [1, 10000]
+                let shares: u64 = res.cpu_share.parse()
+                    .map_err(|e| format!("Invalid cpu share {:?} : {}", res.cpu_share, e))?;
+                let weight = 1 + (shares.max(2).min(262144) - 2) * 9999 / 262142;
+                write_cgroup_file(&subsys_cgroup_path, "cpu.weight", &weight.to_string())?;
+            } else {
+                write_cgroup_file(&subsys_cgroup_path, "cpu.shares", &res.cpu_share)?;
+            }
+        }
+        if res.cpus != "" {
+            let cpus: f64 = res.cpus.parse().map_err(|e| format!("Invalid cpus {:?} : {}", res.cpus, e))?;
+            let quota = (cpus * CPU_PERIOD as f64) as u64;
+            if v2 {
+                write_cgroup_file(&subsys_cgroup_path, "cpu.max", &format!("{} {}", quota, CPU_PERIOD))?;
+            } else {
+                write_cgroup_file(&subsys_cgroup_path, "cpu.cfs_period_us", &CPU_PERIOD.to_string())?;
+                write_cgroup_file(&subsys_cgroup_path, "cpu.cfs_quota_us", &quota.to_string())?;
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct CpusetSubSystem {}
 
 impl Subsystem for CpusetSubSystem {
     pub fn name() -> String {
-        "cpuseet".to_string()
+        "cpuset".to_string()
+    }
+
+    fn set(&self, cgroup_path: &Path, res: &ResourceConfig) -> Result<(), String> {
+        let subsys_cgroup_path = get_cgroup_path(Self::name(), cgroup_path, true)?;
+        if !is_cgroup_v2() {
+            // a v1 cpuset starts out empty and refuses tasks until cpus and mems are set
+            let cgroup_root = find_cgroup_mountpoint(Path::new(&Self::name()))?;
+            let mut current = cgroup_root.clone();
+            for component in cgroup_path.components() {
+                let parent = current.clone();
+                current.push(component);
+                for file in &["cpuset.cpus", "cpuset.mems"] {
+                    if read_cgroup_file(&current, file)? == "" {
+                        write_cgroup_file(&current, file, &read_cgroup_file(&parent, file)?)?;
+                    }
+                }
+            }
+        }
+        if res.cpu_set != "" {
+            write_cgroup_file(&subsys_cgroup_path, "cpuset.cpus", &res.cpu_set)?;
+        }
+        Ok(())
     }
 }
 
@@ -90,6 +326,289 @@ impl Subsystem for MemorySubSystem {
     pub fn name() -> String {
         "memory".to_string()
     }
+
+    fn set(&self, cgroup_path: &Path, res: &ResourceConfig) -> Result<(), String> {
+        let subsys_cgroup_path = get_cgroup_path(Self::name(), cgroup_path, true)?;
+        let memory = if res.memory_limit != "" { Some(parse_size(&res.memory_limit)?) } else { None };
+
+        if is_cgroup_v2() {
+            if let Some(m) = memory {
+                write_cgroup_file(&subsys_cgroup_path, "memory.max", &m.to_string())?;
+            }
+            // memory.swap.max only counts swap, docker's --memory-swap is memory + swap
+            if res.memory_swap == "-1" {
+                write_cgroup_file(&subsys_cgroup_path, "memory.swap.max", "max")?;
+            } else if res.memory_swap != "" {
+                let total = parse_size(&res.memory_swap)?;
+                let swap = memory.and_then(|m| total.checked_sub(m)).ok_or(format!(
+                    "--memory-swap {} needs a --memory limit no larger than it", total))?;
+                write_cgroup_file(&subsys_cgroup_path, "memory.swap.max", &swap.to_string())?;
+            }
+            if res.memory_reservation != "" {
+                let reservation = parse_size(&res.memory_reservation)?;
+                write_cgroup_file(&subsys_cgroup_path, "memory.low", &reservation.to_string())?;
+            }
+            if res.kernel_memory != "" {
+                warn!("Kernel memory limit is not supported on cgroup v2, ignoring");
+            }
+            if res.oom_kill_disable == Some(true) {
+                warn!("Disabling the OOM killer is not supported on cgroup v2, ignoring");
+            }
+            if res.memory_swappiness != "" {
+                warn!("Per-cgroup swappiness is not supported on cgroup v2, ignoring");
+            }
+            return Ok(());
+        }
+
+        // the kernel keeps memsw.limit_in_bytes >= limit_in_bytes at every step,
+        // so a growing limit needs the swap limit raised first
+        let swap = if res.memory_swap == "-1" {
+            Some("-1".to_string())
+        } else if res.memory_swap != "" {
+            Some(parse_size(&res.memory_swap)?.to_string())
+        } else {
+            None
+        };
+        let current = read_cgroup_file(&subsys_cgroup_path, "memory.limit_in_bytes")?.parse::<u64>().unwrap_or(0);
+        let swap_first = res.memory_swap == "-1" || memory.map(|m| m > current).unwrap_or(false);
+        if swap_first {
+            if let Some(ref swap) = swap {
+                write_cgroup_file(&subsys_cgroup_path, "memory.memsw.limit_in_bytes", swap)?;
+            }
+        }
+        if let Some(m) = memory {
+            write_cgroup_file(&subsys_cgroup_path, "memory.limit_in_bytes", &m.to_string())?;
+        }
+        if !swap_first {
+            if let Some(ref swap) = swap {
+                write_cgroup_file(&subsys_cgroup_path, "memory.memsw.limit_in_bytes", swap)?;
+            }
+        }
+        if res.memory_reservation != "" {
+            let reservation = parse_size(&res.memory_reservation)?;
+            write_cgroup_file(&subsys_cgroup_path, "memory.soft_limit_in_bytes", &reservation.to_string())?;
+        }
+        if res.kernel_memory != "" {
+            let kmem = parse_size(&res.kernel_memory)?;
+            if let Err(e) = write_cgroup_file(&subsys_cgroup_path, "memory.kmem.limit_in_bytes", &kmem.to_string()) {
+                warn!("Kernel memory limit is not supported by this kernel: {}", e);
+            }
+        }
+        if let Some(disable) = res.oom_kill_disable {
+            write_cgroup_file(&subsys_cgroup_path, "memory.oom_control", if disable { "1" } else { "0" })?;
+        }
+        if res.memory_swappiness != "" {
+            write_cgroup_file(&subsys_cgroup_path, "memory.swappiness", &res.memory_swappiness)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct BlkioSubSystem {}
+
+impl Subsystem for BlkioSubSystem {
+    fn name() -> String {
+        if is_cgroup_v2() { "io".to_string() } else { "blkio".to_string() }
+    }
+
+    fn set(&self, cgroup_path: &Path, res: &ResourceConfig) -> Result<(), String> {
+        let subsys_cgroup_path = get_cgroup_path(Self::name(), cgroup_path, true)?;
+        let v2 = is_cgroup_v2();
+
+        if res.blkio_weight != "" {
+            let weight: u32 = res.blkio_weight.parse()
+                .map_err(|e| format!("Invalid blkio weight {:?} : {}", res.blkio_weight, e))?;
+            if weight < 10 || weight > 1000 {
+                return Err(format!("blkio weight {} out of range [10, 1000]", weight));
+            }
+            if v2 {
+                // map the v1 weight range [10, 1000] onto the v2 range [1, 10000]
+                let weight = 1 + (weight - 10) * 9999 / 990;
+                write_cgroup_file(&subsys_cgroup_path, "io.weight", &format!("default {}", weight))?;
+            } else {
+                write_cgroup_file(&subsys_cgroup_path, "blkio.weight", &weight.to_string())?;
+            }
+        }
+
+        let throttles = [
+            (&res.device_read_bps, "blkio.throttle.read_bps_device", "rbps", parse_size as fn(&str) -> Result<u64, String>),
+            (&res.device_write_bps, "blkio.throttle.write_bps_device", "wbps", parse_size),
+            (&res.device_read_iops, "blkio.throttle.read_iops_device", "riops", parse_count),
+            (&res.device_write_iops, "blkio.throttle.write_iops_device", "wiops", parse_count),
+        ];
+        for &(args, v1_file, v2_key, parse_rate) in throttles.iter() {
+            for arg in args {
+                let (dev, rate) = parse_device_rate(arg, parse_rate)?;
+                if v2 {
+                    write_cgroup_file(&subsys_cgroup_path, "io.max", &format!("{} {}={}", dev, v2_key, rate))?;
+                } else {
+                    write_cgroup_file(&subsys_cgroup_path, v1_file, &format!("{} {}", dev, rate))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct PidsSubSystem {}
+
+impl Subsystem for PidsSubSystem {
+    fn name() -> String {
+        "pids".to_string()
+    }
+
+    fn set(&self, cgroup_path: &Path, res: &ResourceConfig) -> Result<(), String> {
+        let subsys_cgroup_path = get_cgroup_path(Self::name(), cgroup_path, true)?;
+        if res.pids_limit == "" {
+            return Ok(());
+        }
+        let limit = if res.pids_limit == "-1" { "max" } else { &res.pids_limit[..] };
+        write_cgroup_file(&subsys_cgroup_path, "pids.max", limit)
+    }
+}
+
+pub struct DevicesSubSystem {}
+
+impl Subsystem for DevicesSubSystem {
+    fn name() -> String {
+        "devices".to_string()
+    }
+
+    fn set(&self, cgroup_path: &Path, res: &ResourceConfig) -> Result<(), String> {
+        // created either way, apply joins every subsystem's cgroup
+        let subsys_cgroup_path = get_cgroup_path(Self::name(), cgroup_path, true)?;
+        // attaching a device program needs CAP_SYS_ADMIN in the initial namespace;
+        // a rootless container can't mknod anyway
+        if is_rootless() {
+            return Ok(());
+        }
+        let mut rules = default_allow_rules();
+        for spec in &res.devices {
+            rules.push(Device::parse(spec)?.rule());
+        }
+        if is_cgroup_v2() {
+            return attach_device_filter(&subsys_cgroup_path, &rules);
+        }
+        write_cgroup_file(&subsys_cgroup_path, "devices.deny", "a")?;
+        for rule in &rules {
+            write_cgroup_file(&subsys_cgroup_path, "devices.allow", &rule.to_cgroup_string())?;
+        }
+        Ok(())
+    }
+}
+
+pub struct FreezerSubSystem {}
+
+impl FreezerSubSystem {
+    /// Freeze or thaw every process in the cgroup and wait until the kernel confirms it.
+    pub fn freeze(&self, cgroup_path: &Path, frozen: bool) -> Result<(), String> {
+        let subsys_cgroup_path = get_cgroup_path(Self::name(), cgroup_path, false)?;
+        let v2 = is_cgroup_v2();
+        if v2 {
+            write_cgroup_file(&subsys_cgroup_path, "cgroup.freeze", if frozen { "1" } else { "0" })?;
+        } else {
+            write_cgroup_file(&subsys_cgroup_path, "freezer.state", if frozen { "FROZEN" } else { "THAWED" })?;
+        }
+
+        for _ in 0..1000 {
+            let done = if v2 {
+                let expected = if frozen { "frozen 1" } else { "frozen 0" };
+                read_cgroup_file(&subsys_cgroup_path, "cgroup.events")?.lines().any(|l| l == expected)
+            } else {
+                // v1 reports FREEZING until every task has been stopped
+                let expected = if frozen { "FROZEN" } else { "THAWED" };
+                read_cgroup_file(&subsys_cgroup_path, "freezer.state")? == expected
+            };
+            if done {
+                return Ok(());
+            }
+            if !v2 && frozen {
+                // kick the freezer again, a task forking during the freeze can stall it
+                write_cgroup_file(&subsys_cgroup_path, "freezer.state", "FROZEN")?;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        if frozen {
+            // don't leave the container stuck half frozen
+            let thaw = if v2 {
+                write_cgroup_file(&subsys_cgroup_path, "cgroup.freeze", "0")
+            } else {
+                write_cgroup_file(&subsys_cgroup_path, "freezer.state", "THAWED")
+            };
+            if let Err(e) = thaw {
+                error!("Thaw cgroup {:?} after a failed freeze error {}", cgroup_path, e);
+            }
+        }
+        Err(format!("Timed out waiting for cgroup {:?} to become {}",
+                    cgroup_path, if frozen { "frozen" } else { "thawed" }))
+    }
 }
 
-pub let subsystems_ins = [&CpuSubSystem{}, &CpusetSubSystem{}, &MemorySubSystem{}]
+impl Subsystem for FreezerSubSystem {
+    fn name() -> String {
+        "freezer".to_string()
+    }
+
+    fn set(&self, cgroup_path: &Path, _res: &ResourceConfig) -> Result<(), String> {
+        // nothing to limit, but pause needs the cgroup to exist on v1
+        get_cgroup_path(Self::name(), cgroup_path, true)?;
+        Ok(())
+    }
+}
+
+pub let subsystems_ins = [&CpuSubSystem{}, &CpusetSubSystem{}, &MemorySubSystem{}, &BlkioSubSystem{}, &PidsSubSystem{}, &DevicesSubSystem{}, &FreezerSubSystem{}]
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1048576").unwrap(), 1 << 20);
+        assert_eq!(parse_size("512k").unwrap(), 512 << 10);
+        assert_eq!(parse_size("10M").unwrap(), 10 << 20);
+        assert_eq!(parse_size(" 1g ").unwrap(), 1 << 30);
+        assert_eq!(parse_size("100b").unwrap(), 100);
+        for size in &["", "m", "1.5g", "-1", "10t", "99999999999999999999g"] {
+            assert!(parse_size(size).is_err(), "{} was accepted", size);
+        }
+    }
+
+    #[test]
+    fn counts_take_no_units() {
+        assert_eq!(parse_count("1000").unwrap(), 1000);
+        assert!(parse_count("1k").is_err());
+        assert!(parse_count("-1").is_err());
+    }
+
+    #[test]
+    fn validate_memory_options() {
+        let res = |memory: &str, swap: &str| ResourceConfig {
+            memory_limit: memory.to_string(),
+            memory_swap: swap.to_string(),
+            ..ResourceConfig::default()
+        };
+        assert!(res("100m", "200m").validate().is_ok());
+        assert!(res("100m", "-1").validate().is_ok());
+        assert!(res("200m", "100m").validate().is_err());
+        assert!(res("", "100m").validate().is_err());
+        let swappiness = ResourceConfig { memory_swappiness: "101".to_string(), ..ResourceConfig::default() };
+        assert!(swappiness.validate().is_err());
+        let cpus = ResourceConfig { cpus: "0".to_string(), ..ResourceConfig::default() };
+        assert!(cpus.validate().is_err());
+    }
+
+    #[test]
+    fn merge_overlays_given_options() {
+        let mut res = ResourceConfig {
+            memory_limit: "100m".to_string(),
+            cpus: "1".to_string(),
+            oom_kill_disable: Some(true),
+            ..ResourceConfig::default()
+        };
+        res.merge(&ResourceConfig { cpus: "2".to_string(), ..ResourceConfig::default() });
+        assert_eq!((&res.memory_limit[..], &res.cpus[..], res.oom_kill_disable), ("100m", "2", Some(true)));
+        res.merge(&ResourceConfig { oom_kill_disable: Some(false), ..ResourceConfig::default() });
+        assert_eq!(res.oom_kill_disable, Some(false));
+    }
+}
@@ -0,0 +1,108 @@
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::Error as IoError;
+use std::mem::zeroed;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use libc::{c_char, termios, winsize, posix_openpt, grantpt, unlockpt, ptsname_r, setsid, ioctl};
+use libc::{tcgetattr, tcsetattr, cfmakeraw, isatty};
+use libc::{O_RDWR, O_NOCTTY, O_CLOEXEC, TIOCSCTTY, TIOCGWINSZ, TIOCSWINSZ, TCSANOW, VEOF};
+
+/// A pseudo-terminal pair. The slave end becomes the container's stdio,
+/// the master end with the console server.
+pub struct Pty {
+    pub master: File,
+    pub slave: File
+}
+
+pub fn open_pty() -> Result<Pty, String> {
+    let fd = unsafe { posix_openpt(O_RDWR | O_NOCTTY | O_CLOEXEC) };
+    if fd < 0 {
+        return Err(format!("Can't open pty master : {}", IoError::last_os_error()));
+    }
+    let master = unsafe { File::from_raw_fd(fd) };
+    if unsafe { grantpt(fd) } != 0 || unsafe { unlockpt(fd) } != 0 {
+        return Err(format!("Can't unlock pty : {}", IoError::last_os_error()));
+    }
+    let mut name = [0 as c_char; 128];
+    let rc = unsafe { ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+    if rc != 0 {
+        return Err(format!("Can't get pty slave name : {}", IoError::from_raw_os_error(rc)));
+    }
+    let path = unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned();
+    let slave = OpenOptions::new().read(true).write(true).custom_flags(O_NOCTTY).open(&path)
+        .map_err(|e| format!("Can't open pty slave {} : {}", path, e))?;
+    Ok(Pty { master: master, slave: slave })
+}
+
+/// Start a new session with `fd`, the pty slave, as its controlling terminal.
+pub fn set_controlling_terminal(fd: RawFd) -> Result<(), String> {
+    if unsafe { setsid() } < 0 {
+        return Err(format!("Can't create session : {}", IoError::last_os_error()));
+    }
+    if unsafe { ioctl(fd, TIOCSCTTY, 0) } != 0 {
+        return Err(format!("Can't set controlling terminal : {}", IoError::last_os_error()));
+    }
+    Ok(())
+}
+
+pub fn window_size(fd: RawFd) -> Result<(u16, u16), String> {
+    let mut size: winsize = unsafe { zeroed() };
+    if unsafe { ioctl(fd, TIOCGWINSZ, &mut size) } != 0 {
+        return Err(format!("Can't get window size : {}", IoError::last_os_error()));
+    }
+    Ok((size.ws_row, size.ws_col))
+}
+
+pub fn set_window_size(fd: RawFd, rows: u16, cols: u16) -> Result<(), String> {
+    let mut size: winsize = unsafe { zeroed() };
+    size.ws_row = rows;
+    size.ws_col = cols;
+    if unsafe { ioctl(fd, TIOCSWINSZ, &size) } != 0 {
+        return Err(format!("Can't set window size : {}", IoError::last_os_error()));
+    }
+    Ok(())
+}
+
+/// The character that ends input on the terminal, ctrl-d unless changed.
+pub fn eof_char(fd: RawFd) -> Result<u8, String> {
+    let mut attrs: termios = unsafe { zeroed() };
+    if unsafe { tcgetattr(fd, &mut attrs) } != 0 {
+        return Err(format!("Can't get terminal attributes : {}", IoError::last_os_error()));
+    }
+    Ok(attrs.c_cc[VEOF])
+}
+
+/// Keeps a terminal in raw mode until dropped, so keys like ctrl-c reach
+/// the container instead of signalling `run`.
+pub struct RawMode {
+    fd: RawFd,
+    saved: termios
+}
+
+impl RawMode {
+    /// `None` if `fd` is not a terminal, there is nothing to switch then.
+    pub fn enter(fd: RawFd) -> Result<Option<RawMode>, String> {
+        if unsafe { isatty(fd) } != 1 {
+            return Ok(None);
+        }
+        let mut saved: termios = unsafe { zeroed() };
+        if unsafe { tcgetattr(fd, &mut saved) } != 0 {
+            return Err(format!("Can't get terminal attributes : {}", IoError::last_os_error()));
+        }
+        let mut raw = saved;
+        unsafe { cfmakeraw(&mut raw) };
+        if unsafe { tcsetattr(fd, TCSANOW, &raw) } != 0 {
+            return Err(format!("Can't set raw mode : {}", IoError::last_os_error()));
+        }
+        Ok(Some(RawMode { fd: fd, saved: saved }))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if unsafe { tcsetattr(self.fd, TCSANOW, &self.saved) } != 0 {
+            error!("Restore terminal error {}", IoError::last_os_error());
+        }
+    }
+}
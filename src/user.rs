@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error as IoError};
+use libc::{gid_t, setgid, setgroups, setuid};
+
+/// The identity the user command runs with, resolved inside the container.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecUser {
+    pub uid: u32,
+    pub gid: u32,
+    pub additional_gids: Vec<u32>,
+    pub home: String
+}
+
+struct PasswdEntry {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: String
+}
+
+struct GroupEntry {
+    name: String,
+    gid: u32,
+    members: Vec<String>
+}
+
+fn read_colon_file<R: BufRead>(r: R) -> Vec<Vec<String>> {
+    // a read error ends the file rather than repeating forever
+    r.lines()
+        .map_while(Result::ok)
+        .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+        .map(|l| l.split(':').map(|f| f.to_string()).collect())
+        .collect()
+}
+
+fn parse_passwd<R: BufRead>(r: R) -> Vec<PasswdEntry> {
+    read_colon_file(r).into_iter()
+        .filter(|f| f.len() >= 6)
+        .filter_map(|f| Some(PasswdEntry {
+            name: f[0].clone(),
+            uid: f[2].parse().ok()?,
+            gid: f[3].parse().ok()?,
+            home: f[5].clone()
+        }))
+        .collect()
+}
+
+fn parse_group<R: BufRead>(r: R) -> Vec<GroupEntry> {
+    read_colon_file(r).into_iter()
+        .filter(|f| f.len() >= 3)
+        .filter_map(|f| Some(GroupEntry {
+            name: f[0].clone(),
+            gid: f[2].parse().ok()?,
+            members: f.get(3).map(|m| m.split(',').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect())
+                .unwrap_or_default()
+        }))
+        .collect()
+}
+
+/// Resolve `--user` (`name`, `uid`, `name:group` or `uid:gid`) against the
+/// container's /etc/passwd and /etc/group. Must run after the root switch.
+pub fn resolve_user(spec: &str) -> Result<ExecUser, String> {
+    // images without /etc/passwd can still run numeric users
+    let passwd = File::open("/etc/passwd").map(|f| parse_passwd(BufReader::new(f))).unwrap_or_default();
+    let groups = File::open("/etc/group").map(|f| parse_group(BufReader::new(f))).unwrap_or_default();
+    lookup_user(spec, &passwd, &groups)
+}
+
+fn lookup_user(spec: &str, passwd: &[PasswdEntry], groups: &[GroupEntry]) -> Result<ExecUser, String> {
+    let mut parts = spec.splitn(2, ':');
+    let user_part = parts.next().unwrap_or("");
+    let group_part = parts.next();
+
+    let entry = passwd.iter().find(|e| e.name == user_part)
+        .or_else(|| user_part.parse::<u32>().ok().and_then(|uid| passwd.iter().find(|e| e.uid == uid)));
+    let mut user = match (entry, user_part.parse::<u32>()) {
+        (Some(e), _) => ExecUser { uid: e.uid, gid: e.gid, additional_gids: Vec::new(), home: e.home.clone() },
+        (None, Ok(uid)) => ExecUser { uid: uid, gid: 0, additional_gids: Vec::new(), home: "/".to_string() },
+        (None, Err(_)) => return Err(format!("Unable to find user {}: no matching entries in passwd file", user_part)),
+    };
+
+    if let Some(group_part) = group_part {
+        user.gid = match groups.iter().find(|g| g.name == group_part) {
+            Some(g) => g.gid,
+            None => group_part.parse()
+                .map_err(|_| format!("Unable to find group {}: no matching entries in group file", group_part))?,
+        };
+    } else if let Some(e) = entry {
+        // supplementary groups only come with a named user and no explicit group
+        user.additional_gids = groups.iter()
+            .filter(|g| g.members.contains(&e.name) && g.gid != user.gid)
+            .map(|g| g.gid)
+            .collect();
+    }
+    Ok(user)
+}
+
+/// Switch the calling process to `user`. Groups first, setuid() would take
+/// away the right to change them.
+pub fn switch_user(user: &ExecUser) -> Result<(), String> {
+    let gids: Vec<gid_t> = user.additional_gids.iter().map(|g| *g as gid_t).collect();
+    if unsafe { setgroups(gids.len(), gids.as_ptr()) } != 0 {
+        return Err(format!("Can't setgroups {:?} : {}", gids, IoError::last_os_error()));
+    }
+    if unsafe { setgid(user.gid) } != 0 {
+        return Err(format!("Can't setgid {} : {}", user.gid, IoError::last_os_error()));
+    }
+    if unsafe { setuid(user.uid) } != 0 {
+        return Err(format!("Can't setuid {} : {}", user.uid, IoError::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/sh\n\
+                          # a comment\n\
+                          \n\
+                          app:x:1000:1000::/home/app:/bin/sh\n\
+                          broken:x:notanumber:1\n";
+    const GROUP: &str = "root:x:0:\n\
+                         wheel:x:10:app,other\n\
+                         app:x:1000:app\n\
+                         audio:x:63:other\n";
+
+    fn lookup(spec: &str) -> Result<ExecUser, String> {
+        lookup_user(spec, &parse_passwd(PASSWD.as_bytes()), &parse_group(GROUP.as_bytes()))
+    }
+
+    #[test]
+    fn skips_comments_and_malformed_lines() {
+        let passwd = parse_passwd(PASSWD.as_bytes());
+        assert_eq!(passwd.iter().map(|e| &e.name[..]).collect::<Vec<_>>(), vec!["root", "app"]);
+        let groups = parse_group(GROUP.as_bytes());
+        assert_eq!(groups[1].members, vec!["app", "other"]);
+        assert!(groups[0].members.is_empty());
+    }
+
+    #[test]
+    fn named_user_gets_supplementary_groups() {
+        assert_eq!(lookup("app").unwrap(), ExecUser {
+            uid: 1000, gid: 1000, additional_gids: vec![10], home: "/home/app".to_string()
+        });
+        assert_eq!(lookup("1000").unwrap().home, "/home/app");
+    }
+
+    #[test]
+    fn explicit_group() {
+        let user = lookup("app:audio").unwrap();
+        assert_eq!((user.uid, user.gid), (1000, 63));
+        assert!(user.additional_gids.is_empty());
+        assert_eq!(lookup("app:4242").unwrap().gid, 4242);
+        assert!(lookup("app:nosuchgroup").is_err());
+    }
+
+    #[test]
+    fn numeric_user_without_entry() {
+        assert_eq!(lookup("4242").unwrap(), ExecUser {
+            uid: 4242, gid: 0, additional_gids: Vec::new(), home: "/".to_string()
+        });
+        assert_eq!(lookup("4242:4243").unwrap().gid, 4243);
+        assert!(lookup("nosuchuser").is_err());
+    }
+}
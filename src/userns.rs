@@ -0,0 +1,196 @@
+use std::ffi::CString;
+use std::fs::{read_dir, symlink_metadata, File};
+use std::io::{BufRead, BufReader, Error as IoError, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use libc::{c_void, geteuid, getegid, lchown, chmod, lgetxattr, lsetxattr, ENODATA, ENOTSUP, ERANGE};
+use unshare::{Command, GidMap, Namespace, UidMap};
+
+/// The user docker-style `--userns-remap default` maps containers to.
+pub const DEFAULT_REMAP_USER: &str = "dockremap";
+
+/// A contiguous range of ids: `container_id..container_id+size` inside maps
+/// to `host_id..host_id+size` outside.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct IdMap {
+    pub container_id: u32,
+    pub host_id: u32,
+    pub size: u32
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IdMappings {
+    pub uid_maps: Vec<IdMap>,
+    pub gid_maps: Vec<IdMap>
+}
+
+fn to_host(maps: &[IdMap], id: u32) -> Option<u32> {
+    maps.iter()
+        .find(|m| id >= m.container_id && id - m.container_id < m.size)
+        .map(|m| m.host_id + (id - m.container_id))
+}
+
+impl IdMappings {
+    /// Map the container's root to the start of `user`'s ranges in /etc/subuid and /etc/subgid.
+    pub fn for_user(user: &str) -> Result<IdMappings, String> {
+        let user = if user == "default" { DEFAULT_REMAP_USER } else { user };
+        let (uid, gid) = lookup_user(user)?;
+        Ok(IdMappings {
+            uid_maps: vec![subordinate_range("/etc/subuid", user, uid)?],
+            gid_maps: vec![subordinate_range("/etc/subgid", user, gid)?]
+        })
+    }
+
+    /// Map root to the calling user, and the rest of the container's ids to
+    /// the user's subordinate ranges. This is the only mapping rootless mode allows.
+    pub fn rootless() -> Result<IdMappings, String> {
+        let (uid, gid) = unsafe { (geteuid(), getegid()) };
+        let user = lookup_user_name(uid)?;
+        let mut uid_range = subordinate_range("/etc/subuid", &user, uid)?;
+        let mut gid_range = subordinate_range("/etc/subgid", &user, gid)?;
+        uid_range.container_id = 1;
+        gid_range.container_id = 1;
+        Ok(IdMappings {
+            uid_maps: vec![IdMap { container_id: 0, host_id: uid, size: 1 }, uid_range],
+            gid_maps: vec![IdMap { container_id: 0, host_id: gid, size: 1 }, gid_range]
+        })
+    }
+
+    pub fn host_uid(&self, uid: u32) -> Option<u32> {
+        to_host(&self.uid_maps, uid)
+    }
+
+    pub fn host_gid(&self, gid: u32) -> Option<u32> {
+        to_host(&self.gid_maps, gid)
+    }
+
+    /// The host ids owning files the container's root should own.
+    pub fn root_pair(&self) -> Result<(u32, u32), String> {
+        match (self.host_uid(0), self.host_gid(0)) {
+            (Some(uid), Some(gid)) => Ok((uid, gid)),
+            _ => Err("user namespace mapping has no entry for root".to_string()),
+        }
+    }
+
+    /// Create the command in a new user namespace with these mappings. As
+    /// root the maps are written directly, otherwise via newuidmap/newgidmap.
+    pub fn apply(&self, cmd: &mut Command) {
+        cmd.unshare(&[Namespace::User]);
+        cmd.set_id_maps(
+            self.uid_maps.iter().map(|m| UidMap { inside_uid: m.container_id, outside_uid: m.host_id, count: m.size }).collect(),
+            self.gid_maps.iter().map(|m| GidMap { inside_gid: m.container_id, outside_gid: m.host_id, count: m.size }).collect());
+        if unsafe { geteuid() } != 0 {
+            cmd.set_id_map_commands("/usr/bin/newuidmap", "/usr/bin/newgidmap");
+        }
+    }
+}
+
+fn lookup_user_name(uid: u32) -> Result<String, String> {
+    let f = File::open("/etc/passwd").map_err(|e| format!("Can't open /etc/passwd : {}", e))?;
+    for line in BufReader::new(f).lines() {
+        let line = line.map_err(|e| format!("Error reading /etc/passwd : {}", e))?;
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() >= 3 && fields[2] == uid.to_string() {
+            return Ok(fields[0].to_string());
+        }
+    }
+    // subuid entries may be keyed by the numeric uid as well
+    Ok(uid.to_string())
+}
+
+fn lookup_user(user: &str) -> Result<(u32, u32), String> {
+    let f = File::open("/etc/passwd").map_err(|e| format!("Can't open /etc/passwd : {}", e))?;
+    for line in BufReader::new(f).lines() {
+        let line = line.map_err(|e| format!("Error reading /etc/passwd : {}", e))?;
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() >= 4 && fields[0] == user {
+            let uid = fields[2].parse().map_err(|e| format!("Invalid uid for {} : {}", user, e))?;
+            let gid = fields[3].parse().map_err(|e| format!("Invalid gid for {} : {}", user, e))?;
+            return Ok((uid, gid));
+        }
+    }
+    Err(format!("User {} not found in /etc/passwd", user))
+}
+
+/// Find the `name:start:count` entry for the user (by name or numeric id).
+fn subordinate_range(file: &str, user: &str, id: u32) -> Result<IdMap, String> {
+    let f = File::open(file).map_err(|e| format!("Can't open {} : {}", file, e))?;
+    for line in BufReader::new(f).lines() {
+        let line = line.map_err(|e| format!("Error reading {} : {}", file, e))?;
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        if fields.len() != 3 || (fields[0] != user && fields[0] != id.to_string()) {
+            continue;
+        }
+        let start = fields[1].parse().map_err(|e| format!("Invalid range start in {} : {}", file, e))?;
+        let size = fields[2].parse().map_err(|e| format!("Invalid range size in {} : {}", file, e))?;
+        return Ok(IdMap { container_id: 0, host_id: start, size: size });
+    }
+    Err(format!("No subordinate ids for {} in {}", user, file))
+}
+
+/// Whether the calling process lives in a user namespace other than the initial one.
+pub fn in_user_namespace() -> bool {
+    let mut content = String::new();
+    if File::open("/proc/self/uid_map").and_then(|mut f| f.read_to_string(&mut content)).is_err() {
+        return false;
+    }
+    let fields: Vec<&str> = content.split_whitespace().collect();
+    fields != ["0", "0", "4294967295"]
+}
+
+/// Shift the owner of every file below `path` from container ids to the
+/// host ids they map to, so the tree looks unchanged from inside.
+pub fn shift_ownership(path: &Path, mappings: &IdMappings) -> Result<(), String> {
+    let meta = symlink_metadata(path).map_err(|e| format!("Can't stat {:?} : {}", path, e))?;
+    let uid = mappings.host_uid(meta.uid()).unwrap_or(meta.uid());
+    let gid = mappings.host_gid(meta.gid()).unwrap_or(meta.gid());
+    let c_path = CString::new(path.to_string_lossy().as_bytes()).unwrap();
+    // chown drops setuid/setgid bits and file capabilities, both are put back after
+    let capability = if meta.file_type().is_file() { file_capability(&c_path, path)? } else { None };
+    if unsafe { lchown(c_path.as_ptr(), uid, gid) } != 0 {
+        return Err(format!("Can't chown {:?} : {}", path, IoError::last_os_error()));
+    }
+    if meta.file_type().is_file() {
+        if meta.mode() & 0o6000 != 0 && unsafe { chmod(c_path.as_ptr(), meta.mode() & 0o7777) } != 0 {
+            return Err(format!("Can't restore mode of {:?} : {}", path, IoError::last_os_error()));
+        }
+        if let Some(value) = capability {
+            if unsafe { lsetxattr(c_path.as_ptr(), CAPABILITY_XATTR.as_ptr() as *const _,
+                                  value.as_ptr() as *const c_void, value.len(), 0) } != 0 {
+                return Err(format!("Can't restore capabilities of {:?} : {}", path, IoError::last_os_error()));
+            }
+        }
+    }
+    if meta.is_dir() {
+        let entries = read_dir(path).map_err(|e| format!("Can't read {:?} : {}", path, e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Can't read {:?} : {}", path, e))?;
+            shift_ownership(&entry.path(), mappings)?;
+        }
+    }
+    Ok(())
+}
+
+const CAPABILITY_XATTR: &[u8] = b"security.capability\0";
+
+/// The raw `security.capability` xattr of a file, None if it has none.
+fn file_capability(c_path: &CString, path: &Path) -> Result<Option<Vec<u8>>, String> {
+    let name = CAPABILITY_XATTR.as_ptr() as *const _;
+    let mut value = vec![0u8; 64];
+    loop {
+        let n = unsafe { lgetxattr(c_path.as_ptr(), name, value.as_mut_ptr() as *mut c_void, value.len()) };
+        if n >= 0 {
+            value.truncate(n as usize);
+            return Ok(Some(value));
+        }
+        let err = IoError::last_os_error();
+        match err.raw_os_error() {
+            Some(ENODATA) | Some(ENOTSUP) => return Ok(None),
+            Some(ERANGE) => {
+                let len = value.len() * 2;
+                value.resize(len, 0);
+            }
+            _ => return Err(format!("Can't read capabilities of {:?} : {}", path, err)),
+        }
+    }
+}